@@ -0,0 +1,38 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with jitter, used whenever we need to
+/// retry a connection without hammering the other side.
+pub struct Backoff {
+    attempt: u32,
+    initial: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            attempt: 0,
+            initial,
+            max,
+        }
+    }
+
+    /// How many delays were handed out since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Doubles the delay on every call up to the max, the real delay is
+    /// somewhere between half and all of it so a bunch of servers that
+    /// dropped at once don't all retry at the same moment.
+    pub fn next_delay(&mut self) -> Duration {
+        let exponential = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt += 1;
+        let half = exponential / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
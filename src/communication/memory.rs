@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
+
+use super::bus::{Acker, Deliveries, Delivery, MessageBus, OutboundMessage};
+
+/// Broker living inside the process, for tests and for trying Bors
+/// out without RabbitMQ. Its `InMemoryHandle` plays the general server.
+pub struct InMemoryBus {
+    /// Shared so consuming again after a reconnect picks up where we were
+    commands: Arc<Mutex<UnboundedReceiver<InboundMessage>>>,
+    published: UnboundedSender<OutboundMessage>,
+    acked: Arc<AtomicUsize>,
+}
+
+pub struct InMemoryHandle {
+    commands: UnboundedSender<InboundMessage>,
+    published: UnboundedReceiver<OutboundMessage>,
+    acked: Arc<AtomicUsize>,
+}
+
+/// A command on its way in, with the properties a broker would add
+struct InboundMessage {
+    data: Vec<u8>,
+    correlation_id: Option<String>,
+    reply_to: Option<String>,
+}
+
+impl InMemoryBus {
+    pub fn new() -> (Self, InMemoryHandle) {
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (published_tx, published_rx) = mpsc::unbounded_channel();
+        let acked = Arc::new(AtomicUsize::new(0));
+        let bus = Self {
+            commands: Arc::new(Mutex::new(commands_rx)),
+            published: published_tx,
+            acked: acked.clone(),
+        };
+        let handle = InMemoryHandle {
+            commands: commands_tx,
+            published: published_rx,
+            acked,
+        };
+        (bus, handle)
+    }
+}
+
+impl InMemoryHandle {
+    /// Sends a command the way it would arrive from the broker
+    pub fn send(
+        &self,
+        data: impl Into<Vec<u8>>,
+        correlation_id: Option<String>,
+        reply_to: Option<String>,
+    ) {
+        let command = InboundMessage {
+            data: data.into(),
+            correlation_id,
+            reply_to,
+        };
+        self.commands.send(command).unwrap_or_default();
+    }
+
+    /// The next message Bors published, None once the bus is gone
+    pub async fn next_published(&mut self) -> Option<OutboundMessage> {
+        self.published.recv().await
+    }
+
+    /// How many commands Bors acked so far
+    pub fn acked(&self) -> usize {
+        self.acked.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl MessageBus for InMemoryBus {
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn consume(&self) -> anyhow::Result<Deliveries> {
+        let state = (self.commands.clone(), self.acked.clone());
+        Ok(stream::unfold(state, |(commands, acked)| async move {
+            let command = match commands.lock().await.recv().await {
+                Some(command) => command,
+                // the handle going away (stdin at EOF) isn't the broker going
+                // away, there just won't be any more commands
+                None => std::future::pending().await,
+            };
+            let delivery = Delivery {
+                data: command.data,
+                correlation_id: command.correlation_id,
+                reply_to: command.reply_to,
+                acker: Box::new(MemoryAcker(acked.clone())),
+            };
+            Some((Ok(delivery), (commands, acked)))
+        })
+        .boxed())
+    }
+
+    async fn publish(&self, message: &OutboundMessage) -> anyhow::Result<bool> {
+        // the handle being gone just means nobody is listening
+        Ok(self.published.send(message.clone()).is_ok())
+    }
+
+    async fn close(&mut self) {}
+}
+
+/// Only counts, an in-memory command can't be redelivered
+struct MemoryAcker(Arc<AtomicUsize>);
+
+#[async_trait]
+impl Acker for MemoryAcker {
+    async fn ack(self: Box<Self>) -> anyhow::Result<()> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Takes commands as json lines on stdin and prints every
+/// published event as a json line on stdout
+pub fn serve_stdio(handle: InMemoryHandle) {
+    let InMemoryHandle {
+        commands,
+        mut published,
+        ..
+    } = handle;
+    tokio::task::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let command = InboundMessage {
+                data: line.into_bytes(),
+                correlation_id: None,
+                reply_to: None,
+            };
+            if commands.send(command).is_err() {
+                return;
+            }
+        }
+    });
+    tokio::task::spawn(async move {
+        while let Some(message) = published.recv().await {
+            // the error event about it was printed already
+            if message.dead_letter.is_none() {
+                println!("{}", String::from_utf8_lossy(&message.data));
+            }
+        }
+    });
+}
@@ -0,0 +1,180 @@
+use async_nats::header::HeaderMap;
+use async_nats::jetstream::{self, consumer::pull, message::Acker as JetStreamAck, stream};
+use async_trait::async_trait;
+use futures_util::stream::StreamExt;
+use std::time::Duration;
+
+use super::bus::{
+    Acker, Deliveries, Delivery, MessageBus, NoAck, OutboundMessage, DEAD_LETTER_DESTINATION,
+};
+
+const CONSUME_SUBJECT: &str = "main_server_consume";
+const PUBLISH_SUBJECT: &str = "main_server_publish";
+/// JetStream stream holding every subject
+const STREAM: &str = "BORS";
+/// Durable consumer with JetStream, queue group with core NATS,
+/// either way several Bors instances share the commands
+const CONSUMER: &str = "bors";
+/// Limits of the BORS stream, acked commands and the events stay
+/// in it until they're a day old or the stream grows too big
+const STREAM_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const STREAM_MAX_BYTES: i64 = 1024 * 1024 * 1024;
+
+/// NATS transport, the subjects are named after the RabbitMQ queues.
+/// With JetStream commands stay in the stream until acked, core NATS
+/// delivers them at most once so there is nothing to ack.
+pub struct NatsBus {
+    url: String,
+    jetstream: bool,
+    client: Option<async_nats::Client>,
+}
+
+impl NatsBus {
+    pub fn new(url: String, jetstream: bool) -> Self {
+        Self {
+            url,
+            jetstream,
+            client: None,
+        }
+    }
+
+    fn client(&self) -> anyhow::Result<&async_nats::Client> {
+        self.client
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("not connected to nats"))
+    }
+}
+
+#[async_trait]
+impl MessageBus for NatsBus {
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        self.close().await;
+        let client = async_nats::connect(&self.url).await?;
+        if self.jetstream {
+            let context = jetstream::new(client.clone());
+            let config = stream::Config {
+                name: STREAM.to_owned(),
+                subjects: vec![
+                    CONSUME_SUBJECT.to_owned(),
+                    PUBLISH_SUBJECT.to_owned(),
+                    DEAD_LETTER_DESTINATION.to_owned(),
+                ],
+                max_age: STREAM_MAX_AGE,
+                max_bytes: STREAM_MAX_BYTES,
+                ..Default::default()
+            };
+            context.get_or_create_stream(config.clone()).await?;
+            // a stream that already existed keeps its old limits otherwise
+            context.update_stream(config).await?;
+        }
+        self.client = Some(client);
+        Ok(())
+    }
+
+    async fn consume(&self) -> anyhow::Result<Deliveries> {
+        let client = self.client()?.clone();
+        if !self.jetstream {
+            let subscriber = client
+                .queue_subscribe(CONSUME_SUBJECT, CONSUMER.to_owned())
+                .await?;
+            return Ok(subscriber
+                .map(|message| {
+                    // requests get their events on the inbox they came with
+                    let reply_to = header(&message, "reply_to")
+                        .or_else(|| message.reply.as_ref().map(|inbox| inbox.to_string()));
+                    Ok(delivery(message, reply_to, Box::new(NoAck)))
+                })
+                .boxed());
+        }
+        let consumer = jetstream::new(client)
+            .get_stream(STREAM)
+            .await?
+            .get_or_create_consumer(
+                CONSUMER,
+                pull::Config {
+                    durable_name: Some(CONSUMER.to_owned()),
+                    filter_subject: CONSUME_SUBJECT.to_owned(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(consumer
+            .messages()
+            .await?
+            .map(|message| {
+                // the reply subject of a JetStream message is its ack subject
+                let (message, acker) = message?.split();
+                let reply_to = header(&message, "reply_to");
+                Ok(delivery(message, reply_to, Box::new(NatsAcker(acker))))
+            })
+            .boxed())
+    }
+
+    async fn publish(&self, message: &OutboundMessage) -> anyhow::Result<bool> {
+        let client = self.client()?;
+        let mut headers = HeaderMap::new();
+        if let Some(correlation_id) = &message.correlation_id {
+            headers.insert("correlation_id", correlation_id.as_str());
+        }
+        let payload = message.data.clone().into();
+        let mut reply_to = message.reply_to.as_ref();
+        let mut subject = PUBLISH_SUBJECT;
+        if let Some(dead_letter) = &message.dead_letter {
+            headers.insert("reason", dead_letter.reason.as_str());
+            headers.insert("retries", dead_letter.retries.to_string().as_str());
+            reply_to = None;
+            subject = DEAD_LETTER_DESTINATION;
+        }
+        match reply_to {
+            None if self.jetstream => {
+                // resolves once the stream has stored the message
+                jetstream::new(client.clone())
+                    .publish_with_headers(subject, headers, payload)
+                    .await?
+                    .await?;
+            }
+            reply_to => {
+                let subject = reply_to.cloned().unwrap_or_else(|| subject.to_owned());
+                client
+                    .publish_with_headers(subject, headers, payload)
+                    .await?;
+                // the server has everything sent before its flush reply
+                client.flush().await?;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn close(&mut self) {
+        if let Some(client) = self.client.take() {
+            client.flush().await.unwrap_or_default();
+        }
+    }
+}
+
+struct NatsAcker(JetStreamAck);
+
+#[async_trait]
+impl Acker for NatsAcker {
+    async fn ack(self: Box<Self>) -> anyhow::Result<()> {
+        self.0.ack().await.map_err(|err| anyhow::anyhow!(err))
+    }
+}
+
+fn delivery(
+    message: async_nats::Message,
+    reply_to: Option<String>,
+    acker: Box<dyn Acker>,
+) -> Delivery {
+    Delivery {
+        correlation_id: header(&message, "correlation_id"),
+        reply_to,
+        data: message.payload.to_vec(),
+        acker,
+    }
+}
+
+fn header(message: &async_nats::Message, name: &str) -> Option<String> {
+    let value = message.headers.as_ref()?.get(name)?;
+    Some(value.as_str().to_owned())
+}
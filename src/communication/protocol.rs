@@ -0,0 +1,98 @@
+use schemars::schema_for;
+use serde_json::Value;
+
+use super::types::{CommandRejected, Event, EventMessage, GeneralMessage};
+
+/// Bumped whenever a change to `GeneralMessage` or `EventMessage`
+/// would break the general server.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Categories of every `Command` variant, used to tell an unknown
+/// category apart from a known one with a bad payload.
+const COMMAND_CATEGORIES: [&str; 7] = [
+    "connect",
+    "disconnect",
+    "action",
+    "add_relation",
+    "remove_relation",
+    "cancel_action",
+    "list_actions",
+];
+
+/// Wraps an event in the versioned envelope
+pub fn event_message(
+    server_id: Option<String>,
+    correlation_id: Option<String>,
+    event: Event,
+) -> EventMessage {
+    EventMessage {
+        version: PROTOCOL_VERSION,
+        server_id,
+        correlation_id,
+        event,
+    }
+}
+
+/// Parses a raw command from the broker, on failure the error is the
+/// event that should be sent back explaining why it was rejected.
+pub fn decode_command(raw: &str) -> Result<GeneralMessage, EventMessage> {
+    let value: Value = serde_json::from_str(raw)
+        .map_err(|err| rejected(None, None, None, format!("malformed json: {}", err)))?;
+    let category = value["category"].as_str().map(str::to_owned);
+    let correlation_id = value["correlation_id"].as_str().map(str::to_owned);
+    let server_id = value["server_id"]
+        .as_str()
+        .filter(|id| !id.is_empty())
+        .map(str::to_owned);
+
+    if value["version"].as_u64() != Some(PROTOCOL_VERSION as u64) {
+        return Err(rejected(
+            server_id,
+            correlation_id,
+            category,
+            format!(
+                "unsupported protocol version {}, expected {}",
+                value["version"], PROTOCOL_VERSION
+            ),
+        ));
+    }
+    match category.as_deref() {
+        Some(known) if COMMAND_CATEGORIES.contains(&known) => {}
+        Some(unknown) => {
+            let reason = format!("unknown category: {}", unknown);
+            return Err(rejected(server_id, correlation_id, category, reason));
+        }
+        None => {
+            let reason = "missing category".to_owned();
+            return Err(rejected(server_id, correlation_id, None, reason));
+        }
+    }
+    serde_json::from_value(value).map_err(|err| {
+        let reason = format!("malformed payload: {}", err);
+        rejected(server_id, correlation_id, category, reason)
+    })
+}
+
+fn rejected(
+    server_id: Option<String>,
+    correlation_id: Option<String>,
+    category: Option<String>,
+    reason: String,
+) -> EventMessage {
+    event_message(
+        server_id,
+        correlation_id,
+        Event::Error(CommandRejected { category, reason }),
+    )
+}
+
+/// JSON Schema of both directions of the protocol, so the general
+/// server can validate against the exact same contract.
+pub fn export_schema() -> String {
+    let schema = serde_json::json!({
+        "version": PROTOCOL_VERSION,
+        "command": schema_for!(GeneralMessage),
+        "event": schema_for!(EventMessage),
+    });
+    serde_json::to_string_pretty(&schema).unwrap()
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use redis::aio::MultiplexedConnection;
+use redis::streams::{StreamId, StreamMaxlen, StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+
+use super::bus::{
+    Acker, Deliveries, Delivery, MessageBus, OutboundMessage, DEAD_LETTER_DESTINATION,
+};
+
+const CONSUME_STREAM: &str = "main_server_consume";
+const PUBLISH_STREAM: &str = "main_server_publish";
+/// Consumer group shared by every Bors instance
+const GROUP: &str = "bors";
+/// How long a read waits for new commands before asking again
+const BLOCK_MS: usize = 5000;
+const READ_COUNT: usize = 16;
+/// Every stream we add to is trimmed to about this many entries,
+/// the oldest ones go first
+const STREAM_MAX_LEN: usize = 100_000;
+
+/// Redis Streams transport, the streams are named after the RabbitMQ
+/// queues. Commands are read through a consumer group and stay pending
+/// until acked, entries have `data`, `correlation_id` and `reply_to` fields.
+/// Commands we give up on are added to main_server_dead_letter.
+pub struct RedisBus {
+    url: String,
+    consumer: String,
+    client: Option<redis::Client>,
+    connection: Option<MultiplexedConnection>,
+}
+
+impl RedisBus {
+    pub fn new(url: String, consumer: String) -> Self {
+        Self {
+            url,
+            consumer,
+            client: None,
+            connection: None,
+        }
+    }
+
+    fn connection(&self) -> anyhow::Result<MultiplexedConnection> {
+        self.connection
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("not connected to redis"))
+    }
+}
+
+#[async_trait]
+impl MessageBus for RedisBus {
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        self.close().await;
+        let client = redis::Client::open(self.url.as_str())?;
+        let mut conn = client.get_multiplexed_tokio_connection().await?;
+        // from the start of the stream, so commands sent before
+        // the first Bors instance ever came up aren't skipped
+        let created: redis::RedisResult<()> = conn
+            .xgroup_create_mkstream(CONSUME_STREAM, GROUP, "0")
+            .await;
+        match created {
+            // created by an earlier run or another instance
+            Err(err) if err.code() == Some("BUSYGROUP") => {}
+            res => res?,
+        }
+        self.client = Some(client);
+        self.connection = Some(conn);
+        Ok(())
+    }
+
+    async fn consume(&self) -> anyhow::Result<Deliveries> {
+        let client = self
+            .client
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("not connected to redis"))?;
+        // blocking reads get a connection of their own so publishing never waits on them
+        let reader = Reader {
+            conn: client.get_multiplexed_tokio_connection().await?,
+            acks: self.connection()?,
+            consumer: self.consumer.clone(),
+            pending_after: Some("0".to_owned()),
+            read: VecDeque::new(),
+        };
+        Ok(stream::unfold(reader, |mut reader| async move {
+            loop {
+                if let Some(entry) = reader.read.pop_front() {
+                    let delivery = reader.delivery(entry);
+                    return Some((Ok(delivery), reader));
+                }
+                if let Err(err) = reader.read_more().await {
+                    return Some((Err(err), reader));
+                }
+            }
+        })
+        .boxed())
+    }
+
+    async fn publish(&self, message: &OutboundMessage) -> anyhow::Result<bool> {
+        let mut fields = vec![("data", message.data.as_slice())];
+        if let Some(correlation_id) = &message.correlation_id {
+            fields.push(("correlation_id", correlation_id.as_bytes()));
+        }
+        let mut stream = message.reply_to.as_deref().unwrap_or(PUBLISH_STREAM);
+        let retries;
+        if let Some(dead_letter) = &message.dead_letter {
+            retries = dead_letter.retries.to_string();
+            fields.push(("reason", dead_letter.reason.as_bytes()));
+            fields.push(("retries", retries.as_bytes()));
+            stream = DEAD_LETTER_DESTINATION;
+        }
+        // redis answers with the entry id once the entry is added
+        let _: String = self
+            .connection()?
+            .xadd_maxlen(stream, StreamMaxlen::Approx(STREAM_MAX_LEN), "*", &fields)
+            .await?;
+        Ok(true)
+    }
+
+    async fn close(&mut self) {
+        self.connection = None;
+        self.client = None;
+    }
+}
+
+struct Reader {
+    conn: MultiplexedConnection,
+    acks: MultiplexedConnection,
+    consumer: String,
+    /// While set, commands this consumer read before but never acked
+    /// are read again first, the way RabbitMQ redelivers them
+    pending_after: Option<String>,
+    read: VecDeque<StreamId>,
+}
+
+impl Reader {
+    async fn read_more(&mut self) -> anyhow::Result<()> {
+        let options = StreamReadOptions::default()
+            .group(GROUP, &self.consumer)
+            .count(READ_COUNT);
+        let (id, options) = match &self.pending_after {
+            Some(id) => (id.clone(), options),
+            None => (">".to_owned(), options.block(BLOCK_MS)),
+        };
+        let reply: StreamReadReply = self
+            .conn
+            .xread_options(&[CONSUME_STREAM], &[id], &options)
+            .await?;
+        let entries: Vec<StreamId> = reply.keys.into_iter().flat_map(|key| key.ids).collect();
+        if self.pending_after.is_some() {
+            self.pending_after = entries.last().map(|entry| entry.id.clone());
+        }
+        self.read.extend(entries);
+        Ok(())
+    }
+
+    fn delivery(&self, entry: StreamId) -> Delivery {
+        Delivery {
+            data: entry.get("data").unwrap_or_default(),
+            correlation_id: entry.get("correlation_id"),
+            reply_to: entry.get("reply_to"),
+            acker: Box::new(RedisAcker {
+                conn: self.acks.clone(),
+                id: entry.id,
+            }),
+        }
+    }
+}
+
+struct RedisAcker {
+    conn: MultiplexedConnection,
+    id: String,
+}
+
+#[async_trait]
+impl Acker for RedisAcker {
+    async fn ack(mut self: Box<Self>) -> anyhow::Result<()> {
+        let _: i64 = self.conn.xack(CONSUME_STREAM, GROUP, &[&self.id]).await?;
+        Ok(())
+    }
+}
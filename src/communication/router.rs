@@ -1,110 +1,202 @@
 use std::sync::Arc;
 
-use futures::lock::{Mutex, MutexGuard};
-use lapin::Channel;
-use tokio::sync::RwLock;
-use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, instrument, warn, Instrument};
 
 use crate::{
-    communication::{rabbit, types::HOIRelationReq},
-    integration,
-    state::state_types::MainState,
+    communication::bus::Publisher,
+    integration::{connection, server_actor::ServerCommand},
+    state::{metrics::METRICS, state_types::MainState},
 };
 
-use super::types::GeneralMessage;
+use super::types::{Command, CommandRejected, Correlation, Event, GeneralMessage};
 
+/// Why a command couldn't be routed
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    /// Can never succeed, the general server got an error event already
+    Rejected(String),
+    /// Might succeed later, e.g. the server is still being restored.
+    /// Nothing was published, retrying is up to the caller
+    Transient(String),
+}
+
+/// Routes a command from the general server.
+///
+/// Only connecting needs to know the integration type, every
+/// other command targets an already connected server_id.
+#[instrument(name = "command", skip_all, fields(
+    category = msg.command.category(),
+    server_id = %msg.server_id,
+    correlation_id = msg.correlation_id.as_deref(),
+))]
 pub async fn route_rabbit_message(
     msg: GeneralMessage,
-    server_state: &Arc<RwLock<MainState>>,
-    publish_channel: &Arc<Mutex<lapin::Channel>>,
-) {
-    println!("routing message...");
-    println!("{:?}", msg);
-    match msg.category.as_str() {
-        "connect_hoi" => {
+    server_state: &Arc<MainState>,
+    publisher: &Publisher,
+) -> Result<(), RouteError> {
+    let category = msg.command.category();
+    info!("routing command");
+    // GeneralMessage's Debug redacts connect credentials
+    debug!(?msg);
+    METRICS.commands.with_label_values(&[category]).inc();
+    let correlation = msg.correlation();
+    match msg.command {
+        Command::Connect(connect_data) => {
             // tries to connect to the IoT server and sends the response
             // to the main server via rabbitmq
-            if let Ok(credentials) = serde_json::from_str(&msg.data) {
-                tokio::task::spawn(integration::house_of_iot::connect_and_begin_listening(
-                    credentials,
-                    server_state.clone(),
-                    publish_channel.clone(),
-                ));
+            match server_state.integrations.create(
+                &connect_data.integration_type,
+                connect_data.credentials.expose(),
+            ) {
+                Ok(integration) => {
+                    tokio::task::spawn(
+                        connection::connect_and_begin_listening(
+                            integration,
+                            connect_data.polling,
+                            server_state.clone(),
+                            publisher.clone(),
+                            correlation,
+                        )
+                        .in_current_span(),
+                    );
+                }
+                Err(err) => {
+                    return Err(reject(
+                        publisher,
+                        None,
+                        &correlation,
+                        category,
+                        err.to_string(),
+                    ))
+                }
             }
         }
-        "disconnect_hoi" => {
-            let mut write_state = server_state.write().await;
-            let mut channel = publish_channel.lock().await;
-            // clean up iot server from state
-            // which will automatically stop each
-            // task associated with the iot server
-            write_state.action_in_progress.remove(&msg.server_id);
-            write_state.passive_data_skips.remove(&msg.server_id);
-            write_state.passive_in_progress.remove(&msg.server_id);
-            write_state.server_connections.remove(&msg.server_id);
-            write_state.server_credentials.remove(&msg.server_id);
-            write_state.action_execution_queue.remove(&msg.server_id);
-            post_mq_msg(
-                &mut channel,
-                msg.server_id.clone(),
-                String::new(),
-                "disconnected".to_owned(),
+        Command::Disconnect => {
+            disconnect_server(server_state, publisher, msg.server_id, &correlation).await
+        }
+        Command::Action(action_data) => {
+            send_to_server(
+                server_state,
+                publisher,
+                msg.server_id,
+                category,
+                correlation,
+                |correlation| ServerCommand::QueueAction(action_data, correlation),
             )
-            .await;
+            .await?
         }
-        "action_hoi" => {
-            if let Ok(action_data) = serde_json::from_str(&msg.data) {
-                tokio::task::spawn(integration::house_of_iot::queue_up_action_execution(
-                    server_state.clone(),
-                    action_data,
-                    msg.server_id,
-                ));
-            }
+        Command::CancelAction(bot_name) => {
+            send_to_server(
+                server_state,
+                publisher,
+                msg.server_id,
+                category,
+                correlation,
+                |correlation| ServerCommand::CancelAction(bot_name, correlation),
+            )
+            .await?
         }
-        "add_relation" | "remove_relation" => {
-            let write_state = server_state.write().await;
-            let mut channel = publish_channel.lock().await;
-            if let Some(tx) = write_state.server_connections.get(&msg.server_id) {
-                tx.unbounded_send(Message::Text("external_controller_request".to_string()))
-                    .unwrap_or_default();
-                tx.unbounded_send(Message::Text(
-                    serde_json::to_string(&HOIRelationReq {
-                        category: msg.category.clone(),
-                        data: msg.data.clone(),
-                    })
-                    .unwrap(),
-                ))
-                .unwrap_or_default();
-            }
-            post_mq_msg(
-                &mut channel,
-                msg.server_id.clone(),
-                msg.category.clone(),
-                "relation-request-made".to_owned(),
+        Command::ListActions => {
+            send_to_server(
+                server_state,
+                publisher,
+                msg.server_id,
+                category,
+                correlation,
+                ServerCommand::ListActions,
             )
-            .await;
+            .await?
         }
-        _ => {}
+        Command::AddRelation(data) | Command::RemoveRelation(data) => {
+            send_to_server(
+                server_state,
+                publisher,
+                msg.server_id,
+                category,
+                correlation,
+                |correlation| ServerCommand::Relation {
+                    category: category.to_owned(),
+                    data,
+                    correlation,
+                },
+            )
+            .await?
+        }
+    }
+    Ok(())
+}
+
+/// Forgets the server, stops the actor that owns it and lets
+/// the general server know. Also used by the admin API.
+pub async fn disconnect_server(
+    server_state: &Arc<MainState>,
+    publisher: &Publisher,
+    server_id: String,
+    correlation: &Correlation,
+) {
+    // clean up iot server from state
+    // and stop the actor that owns it
+    if let Some(server) = server_state.remove_server(&server_id).await {
+        server.send(ServerCommand::Disconnect);
     }
+    post_mq_msg(publisher, server_id, correlation, Event::Disconnected);
+}
+
+/// Hands a command to the actor of `server_id`, rejecting it if
+/// there is no such server. A server that is persisted but not
+/// running (still being restored, or stopping) may be back soon.
+async fn send_to_server(
+    server_state: &Arc<MainState>,
+    publisher: &Publisher,
+    server_id: String,
+    category: &str,
+    correlation: Correlation,
+    command: impl FnOnce(Correlation) -> ServerCommand,
+) -> Result<(), RouteError> {
+    if let Some(server) = server_state.get_server(&server_id).await {
+        if server.send(command(correlation.clone())) {
+            return Ok(());
+        }
+    }
+    if server_state.store.contains(&server_id) {
+        return Err(RouteError::Transient(
+            "server is not running yet".to_owned(),
+        ));
+    }
+    Err(reject(
+        publisher,
+        Some(server_id),
+        &correlation,
+        category,
+        "unknown server_id".to_owned(),
+    ))
 }
 
 /// Sends message to the queue
 /// so the general server can pick it up
 /// and send it to the room/user that owned. this
 /// request
-async fn post_mq_msg(
-    channel: &mut MutexGuard<'_, Channel>,
-    server_id: String,
-    data: String,
-    category: String,
-) {
-    let msg = GeneralMessage {
-        category,
-        data,
-        server_id,
-    };
+fn post_mq_msg(publisher: &Publisher, server_id: String, correlation: &Correlation, event: Event) {
+    publisher.reply(Some(server_id), event, correlation);
+}
 
-    rabbit::publish_message(channel, serde_json::to_string(&msg).unwrap())
-        .await
-        .unwrap_or_default();
+/// Lets the general server know a well formed command couldn't be executed
+fn reject(
+    publisher: &Publisher,
+    server_id: Option<String>,
+    correlation: &Correlation,
+    category: &str,
+    reason: String,
+) -> RouteError {
+    // the reason can quote credentials that failed to parse
+    warn!("rejecting command");
+    publisher.reply(
+        server_id,
+        Event::Error(CommandRejected {
+            category: Some(category.to_owned()),
+            reason: reason.clone(),
+        }),
+        correlation,
+    );
+    RouteError::Rejected(reason)
 }
@@ -0,0 +1,149 @@
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{fmt, io};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Room for the JSON of any credentials we know of, so serializing
+/// them usually fits in the first buffer
+const CREDENTIALS_CAPACITY: usize = 4096;
+
+/// A password or token, redacted when printed and wiped from
+/// memory once dropped. Use `expose` only where the value is sent on.
+#[derive(Clone, Default)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self(value.to_owned())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+/// Serializes the real value, it's only ever serialized
+/// to be encrypted by the server store.
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self)
+    }
+}
+
+/// The free-form credentials of a connect command as JSON, redacted
+/// when printed and wiped from memory once dropped.
+pub struct Credentials(Zeroizing<Vec<u8>>);
+
+impl Credentials {
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+/// Only there so commands can be written out, e.g. in tests
+impl Serialize for Credentials {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut value: Value = serde_json::from_slice(&self.0).map_err(ser::Error::custom)?;
+        let res = value.serialize(serializer);
+        wipe(&mut value);
+        res
+    }
+}
+
+/// Parsed strings are moved rather than copied on the way here, so
+/// wiping them once they are serialized leaves no plain copy behind
+impl<'de> Deserialize<'de> for Credentials {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut value = Value::deserialize(deserializer)?;
+        let json = credentials_json(&value);
+        wipe(&mut value);
+        Ok(Self(json))
+    }
+}
+
+impl JsonSchema for Credentials {
+    fn schema_name() -> String {
+        "Credentials".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        serde_json::Map::<String, Value>::json_schema(gen)
+    }
+}
+
+/// Overwrites every string in `value`, keys are left alone
+fn wipe(value: &mut Value) {
+    match value {
+        Value::String(string) => string.zeroize(),
+        Value::Array(values) => values.iter_mut().for_each(wipe),
+        Value::Object(map) => map.values_mut().for_each(wipe),
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+/// Serializes credentials as JSON straight into memory
+/// that is wiped once dropped, e.g. to be encrypted.
+pub fn credentials_json(credentials: &impl Serialize) -> Zeroizing<Vec<u8>> {
+    let mut writer = ZeroizingWriter(Zeroizing::new(Vec::with_capacity(CREDENTIALS_CAPACITY)));
+    serde_json::to_writer(&mut writer, credentials).unwrap();
+    writer.0
+}
+
+/// Grows by copying into a bigger buffer and wiping the old one,
+/// where `Vec` would leave the old contents behind in freed memory
+struct ZeroizingWriter(Zeroizing<Vec<u8>>);
+
+impl io::Write for ZeroizingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let needed = self.0.len() + buf.len();
+        if needed > self.0.capacity() {
+            let mut grown = Zeroizing::new(Vec::with_capacity(needed.max(self.0.capacity() * 2)));
+            grown.extend_from_slice(&self.0);
+            // the old buffer is wiped as it's dropped
+            self.0 = grown;
+        }
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
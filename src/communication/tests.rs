@@ -0,0 +1,456 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use serde_json::{json, Value};
+
+use crate::integration::iot_integration::IoTIntegration;
+use crate::integration::server_actor::ServerActor;
+use crate::integration::tests::{
+    fast_polling, hoi_credentials, hoi_state, FakeIntegration, MockHoi,
+};
+use crate::state::persistence::StoredServer;
+
+use super::bus::{self, Deliveries, Delivery, MessageBus, OutboundMessage};
+use super::memory::{InMemoryBus, InMemoryHandle};
+use super::nats::NatsBus;
+use super::protocol;
+use super::rabbit::RabbitBus;
+use super::redis_streams::RedisBus;
+use super::secret::credentials_json;
+use super::types::{Command, HomeAssistantCredentials};
+
+/// Where every transport reads commands from
+const CONSUME_DESTINATION: &str = "main_server_consume";
+
+// The brokers aren't started by the tests, so these are ignored unless
+// run with `cargo test -- --ignored` and BORS_TEST_AMQP_ADDR,
+// BORS_TEST_NATS_URL or BORS_TEST_REDIS_URL pointing them at one.
+// Use a broker nothing else is talking to.
+
+fn broker_url(var: &str) -> String {
+    std::env::var(var).unwrap_or_else(|_| panic!("{} isn't set", var))
+}
+
+#[tokio::test]
+#[ignore = "needs a RabbitMQ broker at BORS_TEST_AMQP_ADDR"]
+async fn rabbitmq_behaves_like_every_bus() {
+    let addr = broker_url("BORS_TEST_AMQP_ADDR");
+    behaves_like_every_bus(Box::new(RabbitBus::new(addr)), true).await;
+}
+
+#[tokio::test]
+#[ignore = "needs a NATS server at BORS_TEST_NATS_URL"]
+async fn core_nats_behaves_like_every_bus() {
+    let url = broker_url("BORS_TEST_NATS_URL");
+    behaves_like_every_bus(Box::new(NatsBus::new(url, false)), false).await;
+}
+
+#[tokio::test]
+#[ignore = "needs a NATS server with JetStream at BORS_TEST_NATS_URL"]
+async fn nats_jetstream_behaves_like_every_bus() {
+    let url = broker_url("BORS_TEST_NATS_URL");
+    behaves_like_every_bus(Box::new(NatsBus::new(url, true)), true).await;
+}
+
+#[tokio::test]
+#[ignore = "needs a Redis server at BORS_TEST_REDIS_URL"]
+async fn redis_streams_behave_like_every_bus() {
+    let url = broker_url("BORS_TEST_REDIS_URL");
+    behaves_like_every_bus(Box::new(RedisBus::new(url, "test".to_owned())), true).await;
+}
+
+#[tokio::test]
+#[ignore = "needs a Redis server at BORS_TEST_REDIS_URL"]
+async fn redis_reads_commands_sent_before_the_group_existed() {
+    let url = broker_url("BORS_TEST_REDIS_URL");
+    let client = redis::Client::open(url.as_str()).unwrap();
+    let mut conn = client.get_multiplexed_tokio_connection().await.unwrap();
+    // like the very first start, the group goes along with the stream
+    redis::cmd("DEL")
+        .arg(CONSUME_DESTINATION)
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .unwrap();
+    redis::cmd("XADD")
+        .arg(CONSUME_DESTINATION)
+        .arg("*")
+        .arg("data")
+        .arg("early")
+        .query_async::<_, String>(&mut conn)
+        .await
+        .unwrap();
+
+    let mut bus = RedisBus::new(url, "test".to_owned());
+    bus.connect().await.unwrap();
+    let mut deliveries = bus.consume().await.unwrap();
+    let delivery = next_matching(&mut deliveries, b"early", Duration::from_secs(10))
+        .await
+        .expect("the command sent before the group existed was skipped");
+    delivery.acker.ack().await.unwrap();
+    bus.close().await;
+}
+
+/// Commands are sent through the bus itself, by publishing
+/// them with the consume destination as reply_to. `durable`
+/// transports keep commands until they are acked.
+async fn behaves_like_every_bus(mut bus: Box<dyn MessageBus>, durable: bool) {
+    bus.connect().await.unwrap();
+    // not valid UTF-8, the bytes have to come through untouched
+    let data = [
+        b"command-".as_slice(),
+        uuid::Uuid::new_v4().as_bytes(),
+        &[0xff],
+    ]
+    .concat();
+    let command = OutboundMessage {
+        data: data.clone(),
+        correlation_id: Some("conformance-1".to_owned()),
+        reply_to: Some(CONSUME_DESTINATION.to_owned()),
+        dead_letter: None,
+    };
+
+    let mut deliveries = if durable {
+        // kept until someone consumes it
+        assert!(bus.publish(&command).await.unwrap());
+        bus.consume().await.unwrap()
+    } else {
+        let deliveries = bus.consume().await.unwrap();
+        assert!(bus.publish(&command).await.unwrap());
+        deliveries
+    };
+    let delivery = next_matching(&mut deliveries, &data, Duration::from_secs(10))
+        .await
+        .expect("the command never arrived");
+    assert_eq!(delivery.correlation_id.as_deref(), Some("conformance-1"));
+    if !durable {
+        delivery.acker.ack().await.unwrap();
+        bus.close().await;
+        return;
+    }
+
+    // unacked, so it comes back after reconnecting
+    drop(delivery);
+    drop(deliveries);
+    bus.close().await;
+    bus.connect().await.unwrap();
+    let mut deliveries = bus.consume().await.unwrap();
+    // JetStream only redelivers once the ack wait (30s) is over
+    let delivery = next_matching(&mut deliveries, &data, Duration::from_secs(60))
+        .await
+        .expect("the unacked command wasn't redelivered");
+    delivery.acker.ack().await.unwrap();
+
+    // acked, so it's gone for good
+    drop(deliveries);
+    bus.close().await;
+    bus.connect().await.unwrap();
+    let mut deliveries = bus.consume().await.unwrap();
+    assert!(
+        next_matching(&mut deliveries, &data, Duration::from_secs(5))
+            .await
+            .is_none()
+    );
+    bus.close().await;
+}
+
+/// The delivery carrying `data`, acking whatever else is still
+/// around from earlier runs. None if it didn't show up in time.
+async fn next_matching(
+    deliveries: &mut Deliveries,
+    data: &[u8],
+    wait: Duration,
+) -> Option<Delivery> {
+    tokio::time::timeout(wait, async {
+        loop {
+            let delivery = deliveries.next().await?.unwrap();
+            if delivery.data == data {
+                return Some(delivery);
+            }
+            delivery.acker.ack().await.unwrap();
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+#[test]
+fn credentials_are_redacted_when_printed() {
+    let credentials: HomeAssistantCredentials = serde_json::from_value(json!({
+        "connection_str": "ws://localhost",
+        "access_token": "very-secret-token",
+        "outside_name": "home",
+        "user_id": 1,
+    }))
+    .unwrap();
+    assert!(!format!("{:?}", credentials).contains("very-secret-token"));
+    assert_eq!(credentials.access_token.to_string(), "[REDACTED]");
+    assert_eq!(credentials.access_token.expose(), "very-secret-token");
+}
+
+#[test]
+fn connect_commands_are_redacted_when_logged() {
+    let msg = protocol::decode_command(
+        &json!({
+            "version": protocol::PROTOCOL_VERSION,
+            "category": "connect",
+            "data": {
+                "integration_type": "home_assistant",
+                "credentials": {"access_token": "very-secret-token"},
+            },
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let logged = format!("{:?}", msg);
+    assert!(logged.contains("home_assistant"));
+    assert!(!logged.contains("very-secret-token"));
+    let Command::Connect(connect_data) = msg.command else {
+        panic!("not a connect command");
+    };
+    let credentials: Value = serde_json::from_slice(connect_data.credentials.expose()).unwrap();
+    assert_eq!(credentials, json!({"access_token": "very-secret-token"}));
+}
+
+#[test]
+fn credentials_of_any_size_are_serialized() {
+    let token = "x".repeat(10_000);
+    let json = credentials_json(&json!({"access_token": token}));
+    let credentials: Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(credentials["access_token"], token.as_str());
+}
+
+/// Waits for the next event of `category` the bus published, skipping dead letters
+async fn expect_on_bus(handle: &mut InMemoryHandle, category: &str) -> (Value, Option<String>) {
+    let wait = async {
+        loop {
+            let message = handle.next_published().await.expect("bus closed");
+            if message.dead_letter.is_some() {
+                continue;
+            }
+            let event: Value = serde_json::from_slice(&message.data).unwrap();
+            if event["category"] == category {
+                return (event, message.correlation_id);
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .unwrap_or_else(|_| panic!("no {} event on the bus", category))
+}
+
+#[tokio::test]
+async fn in_memory_bus_routes_commands_and_shuts_down() {
+    let mock = MockHoi::new(
+        "password",
+        json!([{"active_status": true, "device_name": "lamp", "device_type": "light"}]),
+    );
+    let url = mock.spawn().await;
+    let (state, publisher, outbound) = hoi_state();
+    let (message_bus, mut handle) = InMemoryBus::new();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let running = tokio::task::spawn(bus::run(
+        Box::new(message_bus),
+        state.clone(),
+        publisher,
+        outbound,
+        async {
+            stopped.await.unwrap_or_default();
+        },
+    ));
+
+    // the correlation id from the message properties is used when the body has none
+    handle.send("not json".to_owned(), Some("broken-1".to_owned()), None);
+    let (error, correlation_id) = expect_on_bus(&mut handle, "error").await;
+    assert_eq!(correlation_id.as_deref(), Some("broken-1"));
+    let reason = error["data"]["reason"].as_str().unwrap().to_owned();
+    assert!(reason.starts_with("malformed json"));
+    let dead = handle.next_published().await.unwrap();
+    assert_eq!(dead.data, b"not json");
+    assert_eq!(dead.correlation_id.as_deref(), Some("broken-1"));
+    let dead_letter = dead.dead_letter.unwrap();
+    assert_eq!((dead_letter.reason, dead_letter.retries), (reason, 0));
+    assert_eq!(handle.acked(), 1);
+
+    // parked byte for byte, even when it isn't text
+    let garbage = vec![b'{', 0xff, 0xfe, b'}'];
+    handle.send(garbage.clone(), Some("broken-2".to_owned()), None);
+    expect_on_bus(&mut handle, "error").await;
+    let dead = handle.next_published().await.unwrap();
+    assert_eq!(dead.data, garbage);
+    assert_eq!(handle.acked(), 2);
+
+    // parked without the credentials, which the reason could quote too
+    let mut credentials = hoi_credentials(&url, "hunter2", "admin");
+    credentials["user_id"] = json!("hunter2 \"quoted\"");
+    let broken_connect = json!({
+        "version": protocol::PROTOCOL_VERSION,
+        "category": "connect",
+        "data": {"integration_type": "hoi", "credentials": credentials},
+    });
+    handle.send(
+        broken_connect.to_string(),
+        Some("broken-3".to_owned()),
+        None,
+    );
+    expect_on_bus(&mut handle, "error").await;
+    let dead = handle.next_published().await.unwrap();
+    let parked: Value = serde_json::from_slice(&dead.data).unwrap();
+    assert_eq!(parked["data"]["credentials"], "[REDACTED]");
+    assert_eq!(parked["data"]["integration_type"], "hoi");
+    let reason = dead.dead_letter.unwrap().reason;
+    assert!(reason.contains("invalid type"));
+    assert!(!reason.contains("hunter2"));
+    assert!(reason.contains("[REDACTED]"));
+    assert_eq!(handle.acked(), 3);
+
+    let connect = json!({
+        "version": protocol::PROTOCOL_VERSION,
+        "category": "connect",
+        "data": {
+            "integration_type": "hoi",
+            "credentials": hoi_credentials(&url, "password", "admin"),
+            "polling": fast_polling(),
+        },
+    });
+    handle.send(connect.to_string(), Some("connect-1".to_owned()), None);
+    let (auth, correlation_id) = expect_on_bus(&mut handle, "auth_response").await;
+    assert_eq!(correlation_id.as_deref(), Some("connect-1"));
+    assert_eq!(auth["data"]["passed_auth"], true);
+    let server_id = auth["server_id"].as_str().unwrap().to_owned();
+    expect_on_bus(&mut handle, "passive_data").await;
+    assert!(state.broker.consuming());
+
+    stop.send(()).unwrap();
+    let (disconnected, _) = expect_on_bus(&mut handle, "disconnected").await;
+    assert_eq!(disconnected["server_id"], server_id.as_str());
+    tokio::time::timeout(Duration::from_secs(5), running)
+        .await
+        .expect("bus kept running after shutdown")
+        .unwrap();
+    assert!(!state.broker.connected());
+}
+
+/// Next message the bus published, dead letters included
+async fn next_on_bus(handle: &mut InMemoryHandle) -> OutboundMessage {
+    // long enough for every retry, time is paused in these tests
+    tokio::time::timeout(Duration::from_secs(60), handle.next_published())
+        .await
+        .expect("nothing published")
+        .expect("bus closed")
+}
+
+#[tokio::test(start_paused = true)]
+async fn retries_stop_with_the_consumer() {
+    let (state, publisher, outbound) = hoi_state();
+    state
+        .store
+        .save(&StoredServer {
+            server_id: "restored".to_owned(),
+            integration_type: "fake".to_owned(),
+            credentials: credentials_json(&json!({})),
+            polling: None,
+        })
+        .unwrap();
+    let (message_bus, mut handle) = InMemoryBus::new();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let running = tokio::task::spawn(bus::run(
+        Box::new(message_bus),
+        state.clone(),
+        publisher,
+        outbound,
+        async {
+            stopped.await.unwrap_or_default();
+        },
+    ));
+    let action = json!({
+        "version": protocol::PROTOCOL_VERSION,
+        "category": "action",
+        "server_id": "restored",
+        "data": {"bot_name": "lamp", "action": "turn_on"},
+    });
+    handle.send(action.to_string(), Some("retry-1".to_owned()), None);
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    stop.send(()).unwrap();
+    running.await.unwrap();
+    // left unacked for the broker to redeliver, not dead lettered later on
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    while let Some(message) = handle.next_published().await {
+        assert!(message.dead_letter.is_none());
+    }
+    assert_eq!(handle.acked(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn commands_for_servers_being_restored_are_retried_then_dead_lettered() {
+    let (state, publisher, outbound) = hoi_state();
+    for server_id in ["restored", "never_restored"] {
+        state
+            .store
+            .save(&StoredServer {
+                server_id: server_id.to_owned(),
+                integration_type: "fake".to_owned(),
+                credentials: credentials_json(&json!({})),
+                polling: None,
+            })
+            .unwrap();
+    }
+    let (message_bus, mut handle) = InMemoryBus::new();
+    tokio::task::spawn(bus::run(
+        Box::new(message_bus),
+        state.clone(),
+        publisher.clone(),
+        outbound,
+        futures_util::future::pending(),
+    ));
+    let action = |server_id: &str| {
+        json!({
+            "version": protocol::PROTOCOL_VERSION,
+            "category": "action",
+            "server_id": server_id,
+            "data": {"bot_name": "lamp", "action": "turn_on"},
+        })
+        .to_string()
+    };
+
+    // the command stays unacked while the server is restored
+    handle.send(action("restored"), Some("retry-1".to_owned()), None);
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(handle.acked(), 0);
+    let fake = FakeIntegration::default();
+    let mut integration: Box<dyn IoTIntegration> = Box::new(fake.clone());
+    let frames = integration.connect().await.unwrap();
+    ServerActor::spawn(
+        "restored".to_owned(),
+        integration,
+        None,
+        Some(frames),
+        state.clone(),
+        publisher,
+    )
+    .await;
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    // the fake server never answers, so the actor may have sent it again since
+    assert!(fake.actions.load(Ordering::SeqCst) >= 1);
+    assert_eq!(handle.acked(), 1);
+
+    handle.send(action("never_restored"), Some("retry-2".to_owned()), None);
+    let error = loop {
+        let message = next_on_bus(&mut handle).await;
+        let event: Value = serde_json::from_slice(&message.data).unwrap();
+        if event["category"] == "error" {
+            assert_eq!(message.correlation_id.as_deref(), Some("retry-2"));
+            break event;
+        }
+    };
+    assert_eq!(error["server_id"], "never_restored");
+    assert_eq!(
+        error["data"]["reason"],
+        "server is not running yet, gave up after 5 retries"
+    );
+    let dead = next_on_bus(&mut handle).await;
+    assert_eq!(dead.data, action("never_restored").into_bytes());
+    assert_eq!(dead.dead_letter.unwrap().retries, 5);
+    assert_eq!(handle.acked(), 2);
+}
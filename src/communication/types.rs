@@ -1,18 +1,57 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Deserialize, Serialize, Clone)]
+use super::secret::{Credentials, Secret};
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct HouseOfIoTCredentials {
     //the connection str is usually just the location
     //of the server
     pub connection_str: String,
     pub name_and_type: String,
-    pub password: String,
-    pub admin_password: String,
+    pub password: Secret,
+    pub admin_password: Secret,
     pub outside_name: String,
     pub user_id: i32,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HomeAssistantCredentials {
+    /// Websocket API location, e.g. ws://homeassistant.local:8123/api/websocket
+    pub connection_str: String,
+    /// Long-lived access token created in the HA user profile
+    pub access_token: Secret,
+    pub outside_name: String,
+    pub user_id: i32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MqttCredentials {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    /// Topic layout of the devices behind the broker
+    pub convention: MqttConvention,
+    /// Root topic for zigbee2mqtt, defaults to "zigbee2mqtt"
+    pub base_topic: Option<String>,
+    /// Overrides the topic filters we subscribe to for device state
+    pub state_topics: Option<Vec<String>>,
+    /// Overrides the command topic, "{device}" is replaced by the bot_name
+    pub command_topic: Option<String>,
+    pub outside_name: String,
+    pub user_id: i32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MqttConvention {
+    Zigbee2mqtt,
+    Tasmota,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 pub struct AuthResponse {
     pub user_id: i32,
     pub passed_auth: bool,
@@ -20,34 +59,248 @@ pub struct AuthResponse {
     pub outside_name: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+/// Every command the general server sends us
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
 pub struct GeneralMessage {
-    pub category: String,
-    pub data: String,
+    /// Must match `protocol::PROTOCOL_VERSION`
+    pub version: u32,
+    /// Empty for commands that don't target a connected server (connect)
+    #[serde(default)]
     pub server_id: String,
+    /// Echoed back on every event this command causes, falls
+    /// back to the AMQP correlation_id property when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Taken from the AMQP reply_to property, events caused by this
+    /// command are published there instead of main_server_publish
+    #[serde(skip)]
+    pub reply_to: Option<String>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+impl GeneralMessage {
+    pub fn correlation(&self) -> Correlation {
+        Correlation {
+            correlation_id: self.correlation_id.clone(),
+            reply_to: self.reply_to.clone(),
+        }
+    }
+}
+
+/// Links the events we publish back to the command that caused them
+#[derive(Clone, Debug, Default)]
+pub struct Correlation {
+    pub correlation_id: Option<String>,
+    pub reply_to: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(tag = "category", content = "data", rename_all = "snake_case")]
+pub enum Command {
+    Connect(ConnectData),
+    Disconnect,
+    Action(HOIActionData),
+    AddRelation(String),
+    RemoveRelation(String),
+    /// Holds the bot_name whose pending action should be dropped
+    CancelAction(String),
+    ListActions,
+}
+
+impl Command {
+    /// The category this command is sent under
+    pub fn category(&self) -> &'static str {
+        match self {
+            Command::Connect(_) => "connect",
+            Command::Disconnect => "disconnect",
+            Command::Action(_) => "action",
+            Command::AddRelation(_) => "add_relation",
+            Command::RemoveRelation(_) => "remove_relation",
+            Command::CancelAction(_) => "cancel_action",
+            Command::ListActions => "list_actions",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct ConnectData {
+    /// Key of the integration in the registry, e.g. "hoi"
+    pub integration_type: String,
+    /// Integration specific credentials, for "hoi" these are `HouseOfIoTCredentials`
+    pub credentials: Credentials,
+    /// Overrides the configured poll timing for this server
+    #[serde(default)]
+    pub polling: Option<PollingConfig>,
+}
+
+/// Poll timing in milliseconds and action retries, unset fields
+/// fall back to the config file and then to the built-in defaults.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, Default)]
+pub struct PollingConfig {
+    pub action_interval_ms: Option<u64>,
+    pub passive_interval_ms: Option<u64>,
+    pub max_passive_interval_ms: Option<u64>,
+    pub max_staleness_ms: Option<u64>,
+    /// Full passive_data snapshots are sent this often on top
+    /// of the device events, never if unset
+    pub full_snapshot_interval_ms: Option<u64>,
+    /// Times a timed out action is sent again, 0 disables retrying
+    pub action_retries: Option<u32>,
+}
+
+/// Every event we publish to the general server
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct EventMessage {
+    pub version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_id: Option<String>,
+    /// The correlation_id of the command that caused this event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(tag = "category", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    AuthResponse(AuthResponse),
+    /// A full passive snapshot, sent first and then only
+    /// periodically if full_snapshot_interval_ms is set
+    PassiveData(Vec<Device>),
+    DeviceAdded(Box<Device>),
+    /// Holds the last known state of the device
+    DeviceRemoved(Box<Device>),
+    DeviceStateChanged(Box<Device>),
+    ActionResponse(ActionResult),
+    Disconnected,
+    /// Holds the relation category that was requested
+    #[serde(rename = "relation-request-made")]
+    RelationRequestMade(String),
+    /// Holds the attempt number
+    Reconnecting(u32),
+    Reconnected,
+    Lost,
+    /// A server from before a restart is being served again
+    /// under its old server_id
+    Restored(AuthResponse),
+    /// The IoT server never answered an action in time
+    ActionTimeout(ActionTimeout),
+    /// The IoT server never answered a passive data request in time
+    PassiveTimeout,
+    /// A pending action was cancelled or superseded by a newer action
+    /// for the same bot, sent with the correlation_id of the dropped action
+    ActionCancelled(Box<PendingAction>),
+    /// Answer to `list_actions`, in the order the actions will run
+    PendingActions(Vec<PendingAction>),
+    Error(CommandRejected),
+}
+
+/// The one device shape every integration maps its devices into,
+/// so the general server never sees an IoT server's own format.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Device {
+    /// Unique within its server, actions target it as bot_name
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    /// What the device reports or supports, e.g. "on_off", "brightness"
+    pub capabilities: Vec<String>,
+    /// Current value per capability, "on_off" is always a bool
+    pub state: BTreeMap<String, serde_json::Value>,
+    /// Unit per state entry, for the entries that have one
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub units: BTreeMap<String, String>,
+    /// Unix time in milliseconds we last heard about the device
+    pub last_seen: u64,
+}
+
+impl Device {
+    /// Whether anything but last_seen differs
+    pub fn same_state(&self, other: &Device) -> bool {
+        self.id == other.id
+            && self.name == other.name
+            && self.device_type == other.device_type
+            && self.capabilities == other.capabilities
+            && self.state == other.state
+            && self.units == other.units
+    }
+}
+
+/// Outcome of an action, whatever integration ran it
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct ActionResult {
+    pub device_id: String,
+    pub action: String,
+    pub success: bool,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct PendingAction {
+    pub bot_name: String,
+    pub action: String,
+    pub priority: ActionPriority,
+    pub correlation_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct ActionTimeout {
+    pub bot_name: String,
+    pub action: String,
+    /// Starts at 1 for the first try
+    pub attempt: u32,
+    /// Whether the action is sent again
+    pub retrying: bool,
+}
+
+/// Sent back whenever we couldn't make sense of a command
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+pub struct CommandRejected {
+    /// The category of the rejected command, if we could read it
+    pub category: Option<String>,
+    pub reason: String,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct HOIBasicResponse {
+    #[serde(default)]
     pub server_name: String,
     pub action: String,
     pub status: String,
     pub bot_name: String,
+    #[serde(default)]
     pub target: String,
+    #[serde(default)]
     pub target_value: String,
 }
 
 // The passive data for one HOI bot
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub struct HOIBasicPassiveSingle {
     pub active_status: bool,
     pub device_name: String,
     pub device_type: String,
 }
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
 pub struct HOIActionData {
     pub bot_name: String,
     pub action: String,
+    #[serde(default)]
+    pub priority: ActionPriority,
+}
+
+/// Higher priority actions skip ahead of everything queued below them
+#[derive(
+    Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -0,0 +1,152 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::communication::bus::Publisher;
+use crate::communication::router;
+use crate::communication::types::Correlation;
+use crate::integration::server_actor::{ServerCommand, ServerHandle, ServerStatus};
+use crate::state::state_types::MainState;
+
+#[derive(Clone)]
+struct Admin {
+    server_state: Arc<MainState>,
+    publisher: Publisher,
+    /// Required as a bearer token if set
+    token: Option<String>,
+}
+
+/// Serves the admin API on `addr`, meant for operators on the same host,
+/// e.g. BORS_ADMIN_ADDR=127.0.0.1:9101
+pub async fn serve(addr: String, server_state: Arc<MainState>, publisher: Publisher) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(%addr, %err, "failed to serve admin api");
+            return;
+        }
+    };
+    info!(%addr, "serving admin api");
+    let token = std::env::var("BORS_ADMIN_TOKEN").ok();
+    if let Err(err) = axum::serve(listener, router(server_state, publisher, token)).await {
+        error!(%err, "admin api stopped");
+    }
+}
+
+/// `token`, if set, has to be sent as a bearer token
+pub fn router(server_state: Arc<MainState>, publisher: Publisher, token: Option<String>) -> Router {
+    let admin = Admin {
+        server_state,
+        publisher,
+        token,
+    };
+    Router::new()
+        .route("/servers", get(list_servers))
+        .route("/servers/:server_id", get(get_server))
+        .route("/servers/:server_id/refresh", post(refresh))
+        .route("/servers/:server_id/queue", delete(clear_queue))
+        .route("/servers/:server_id/queue/drain", post(drain_queue))
+        .route("/servers/:server_id/disconnect", post(disconnect))
+        .route_layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin)
+}
+
+async fn authorize(State(admin): State<Admin>, request: Request, next: Next) -> Response {
+    if let Some(token) = &admin.token {
+        let bearer = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if bearer != Some(token.as_str()) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+async fn list_servers(State(admin): State<Admin>) -> Json<Vec<ServerStatus>> {
+    Json(admin.server_state.server_statuses().await)
+}
+
+async fn get_server(
+    State(admin): State<Admin>,
+    Path(server_id): Path<String>,
+) -> Result<Json<ServerStatus>, StatusCode> {
+    let server = find(&admin, &server_id).await?;
+    server
+        .status()
+        .await
+        .map(Json)
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+async fn refresh(
+    State(admin): State<Admin>,
+    Path(server_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    send(&admin, &server_id, ServerCommand::RefreshPassive).await
+}
+
+async fn drain_queue(
+    State(admin): State<Admin>,
+    Path(server_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    send(&admin, &server_id, ServerCommand::DrainQueue).await
+}
+
+async fn clear_queue(
+    State(admin): State<Admin>,
+    Path(server_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let server = find(&admin, &server_id).await?;
+    server
+        .clear_queue()
+        .await
+        .map(Json)
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+async fn disconnect(
+    State(admin): State<Admin>,
+    Path(server_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    find(&admin, &server_id).await?;
+    info!(%server_id, "disconnecting server from the admin api");
+    router::disconnect_server(
+        &admin.server_state,
+        &admin.publisher,
+        server_id,
+        &Correlation::default(),
+    )
+    .await;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn find(admin: &Admin, server_id: &str) -> Result<ServerHandle, StatusCode> {
+    admin
+        .server_state
+        .get_server(server_id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn send(
+    admin: &Admin,
+    server_id: &str,
+    command: ServerCommand,
+) -> Result<StatusCode, StatusCode> {
+    match find(admin, server_id).await?.send(command) {
+        true => Ok(StatusCode::ACCEPTED),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::communication::types::ActionResult;
+use crate::integration::server_actor::ServerStatus;
+use crate::state::state_types::MainState;
+
+#[derive(Serialize)]
+pub struct Status {
+    pub ready: bool,
+    pub broker: BrokerState,
+    pub servers: Vec<ServerHealth>,
+}
+
+/// What `/status` shows of a server, it's served without auth so
+/// users, queues and actions in flight are left to the admin API
+#[derive(Serialize)]
+pub struct ServerHealth {
+    pub server_id: String,
+    pub responding: bool,
+    pub connected: bool,
+    pub last_passive_data: Option<u64>,
+    pub last_action_result: Option<ActionResult>,
+}
+
+impl From<ServerStatus> for ServerHealth {
+    fn from(status: ServerStatus) -> Self {
+        Self {
+            server_id: status.server_id,
+            responding: status.responding,
+            connected: status.connected,
+            last_passive_data: status.last_passive_data,
+            last_action_result: status.last_action_result,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BrokerState {
+    pub connected: bool,
+    pub consuming: bool,
+}
+
+/// Serves the endpoints an orchestrator probes,
+/// e.g. BORS_HEALTH_ADDR=0.0.0.0:9102
+pub async fn serve(addr: String, server_state: Arc<MainState>) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(%addr, %err, "failed to serve health endpoints");
+            return;
+        }
+    };
+    info!(%addr, "serving health endpoints");
+    if let Err(err) = axum::serve(listener, router(server_state)).await {
+        error!(%err, "health endpoints stopped");
+    }
+}
+
+pub fn router(server_state: Arc<MainState>) -> Router {
+    Router::new()
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .route("/status", get(status))
+        .with_state(server_state)
+}
+
+/// Answering at all means the runtime isn't stuck
+async fn live() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Ready once we are connected to the broker and consuming commands
+async fn ready(State(server_state): State<Arc<MainState>>) -> StatusCode {
+    if is_ready(&server_state) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+async fn status(State(server_state): State<Arc<MainState>>) -> Json<Status> {
+    let servers = server_state
+        .server_statuses()
+        .await
+        .into_iter()
+        .map(ServerHealth::from)
+        .collect();
+    Json(Status {
+        ready: is_ready(&server_state),
+        broker: BrokerState {
+            connected: server_state.broker.connected(),
+            consuming: server_state.broker.consuming(),
+        },
+        servers,
+    })
+}
+
+fn is_ready(server_state: &MainState) -> bool {
+    server_state.broker.connected() && server_state.broker.consuming()
+}
@@ -0,0 +1,29 @@
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::state::metrics::METRICS;
+
+/// Serves `/metrics` for Prometheus to scrape on `addr`,
+/// e.g. BORS_METRICS_ADDR=0.0.0.0:9100
+pub async fn serve(addr: String) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!(%addr, %err, "failed to serve metrics");
+            return;
+        }
+    };
+    info!(%addr, "serving metrics");
+    let app = Router::new().route("/metrics", get(metrics));
+    if let Err(err) = axum::serve(listener, app).await {
+        error!(%err, "metrics endpoint stopped");
+    }
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
@@ -0,0 +1,129 @@
+use serde_json::Value;
+use tokio::net::TcpListener;
+
+use crate::communication::bus::Publisher;
+use crate::communication::types::{ActionPriority, Correlation, HOIActionData};
+use crate::integration::server_actor::ServerCommand;
+use crate::integration::tests::{next_published, spawn_actor, FakeIntegration};
+
+use super::{admin, health};
+
+/// Serves `app` on a random local port
+async fn serve_http(app: axum::Router) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::task::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
+
+/// Bare bones HTTP/1.1 client, returns the status code and body
+async fn http(
+    addr: std::net::SocketAddr,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+) -> (u16, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let authorization = token
+        .map(|token| format!("Authorization: Bearer {}\r\n", token))
+        .unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: bors\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        method, path, authorization
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_owned())
+        .unwrap_or_default();
+    (status, body)
+}
+
+#[tokio::test]
+async fn admin_api_inspects_and_manages_servers() {
+    let (state, mut outbound) = spawn_actor(FakeIntegration::default(), None).await;
+    let handle = state.get_server("server").await.unwrap();
+    for bot_name in ["lamp", "fan"] {
+        handle.send(ServerCommand::QueueAction(
+            HOIActionData {
+                bot_name: bot_name.to_owned(),
+                action: "turn_on".to_owned(),
+                priority: ActionPriority::Normal,
+            },
+            Correlation::default(),
+        ));
+    }
+    let (publisher, mut admin_outbound) = Publisher::new();
+    let addr = serve_http(admin::router(
+        state.clone(),
+        publisher,
+        Some("admin".to_owned()),
+    ))
+    .await;
+
+    assert_eq!(http(addr, "GET", "/servers", None).await.0, 401);
+    let (status, body) = http(addr, "GET", "/servers", Some("admin")).await;
+    assert_eq!(status, 200);
+    let servers: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(servers[0]["server_id"], "server");
+    assert_eq!(servers[0]["integration_type"], "fake");
+    assert_eq!(servers[0]["user_id"], 1);
+    assert_eq!(servers[0]["queue"].as_array().unwrap().len(), 2);
+    assert_eq!(servers[0]["action_in_progress"], false);
+
+    let (status, body) = http(addr, "DELETE", "/servers/server/queue", Some("admin")).await;
+    assert_eq!(status, 200);
+    let cleared: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(cleared[0]["bot_name"], "lamp");
+    assert_eq!(cleared[1]["bot_name"], "fan");
+    next_published(&mut outbound, "action_cancelled").await;
+    let (_, body) = http(addr, "GET", "/servers/server", Some("admin")).await;
+    let server: Value = serde_json::from_str(&body).unwrap();
+    assert!(server["queue"].as_array().unwrap().is_empty());
+
+    let refresh = http(addr, "POST", "/servers/server/refresh", Some("admin")).await;
+    assert_eq!(refresh.0, 202);
+    let unknown = http(addr, "POST", "/servers/nope/disconnect", Some("admin")).await;
+    assert_eq!(unknown.0, 404);
+    let disconnect = http(addr, "POST", "/servers/server/disconnect", Some("admin")).await;
+    assert_eq!(disconnect.0, 202);
+    next_published(&mut admin_outbound, "disconnected").await;
+    assert!(state.get_server("server").await.is_none());
+    assert!(state.store.all().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn readiness_follows_the_broker_and_status_lists_servers() {
+    let (state, _outbound) = spawn_actor(FakeIntegration::default(), None).await;
+    let addr = serve_http(health::router(state.clone())).await;
+
+    assert_eq!(http(addr, "GET", "/health/live", None).await.0, 200);
+    assert_eq!(http(addr, "GET", "/health/ready", None).await.0, 503);
+    state.broker.set_connected(true);
+    assert_eq!(http(addr, "GET", "/health/ready", None).await.0, 503);
+    state.broker.set_consuming(true);
+    assert_eq!(http(addr, "GET", "/health/ready", None).await.0, 200);
+
+    let (status, body) = http(addr, "GET", "/status", None).await;
+    assert_eq!(status, 200);
+    let status: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(status["ready"], true);
+    assert_eq!(status["broker"]["consuming"], true);
+    let server = &status["servers"][0];
+    assert_eq!(server["server_id"], "server");
+    assert_eq!(server["responding"], true);
+    assert_eq!(server["connected"], true);
+    assert_eq!(server["last_passive_data"], Value::Null);
+    assert_eq!(server["last_action_result"], Value::Null);
+    // the rest is only for the admin API
+    assert_eq!(server["user_id"], Value::Null);
+    assert_eq!(server["queue"], Value::Null);
+
+    // losing the broker drops consuming with it
+    state.broker.set_connected(false);
+    assert_eq!(http(addr, "GET", "/health/ready", None).await.0, 503);
+}
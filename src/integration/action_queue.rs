@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+
+use crate::communication::types::{Correlation, HOIActionData, PendingAction};
+
+/// An action waiting for its turn, along with the command it came from
+#[derive(Clone, Debug)]
+pub struct QueuedAction {
+    pub action_data: HOIActionData,
+    pub correlation: Correlation,
+}
+
+impl QueuedAction {
+    pub fn to_pending(&self) -> PendingAction {
+        PendingAction {
+            bot_name: self.action_data.bot_name.clone(),
+            action: self.action_data.action.clone(),
+            priority: self.action_data.priority,
+            correlation_id: self.correlation.correlation_id.clone(),
+        }
+    }
+}
+
+/// What happened to an action handed to `ActionQueue::push`
+pub enum Pushed {
+    Queued,
+    /// Queued in place of an older action for the same bot
+    Replaced(QueuedAction),
+    /// The queue is full, the action is handed back
+    Full(QueuedAction),
+}
+
+/// Per server queue of actions, highest priority first and in arrival
+/// order otherwise. Only the latest action per bot is kept, so rapid
+/// toggles of the same bot run once.
+pub struct ActionQueue {
+    pending: VecDeque<QueuedAction>,
+    max_depth: usize,
+}
+
+impl ActionQueue {
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            max_depth,
+        }
+    }
+
+    pub fn push(&mut self, action: QueuedAction) -> Pushed {
+        let replaced = self.cancel(&action.action_data.bot_name);
+        if replaced.is_none() && self.pending.len() >= self.max_depth {
+            return Pushed::Full(action);
+        }
+        let index = self
+            .pending
+            .iter()
+            .position(|queued| queued.action_data.priority < action.action_data.priority)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(index, action);
+        match replaced {
+            Some(replaced) => Pushed::Replaced(replaced),
+            None => Pushed::Queued,
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<QueuedAction> {
+        self.pending.pop_front()
+    }
+
+    /// Removes the pending action for `bot_name`, if there is one
+    pub fn cancel(&mut self, bot_name: &str) -> Option<QueuedAction> {
+        let index = self
+            .pending
+            .iter()
+            .position(|queued| queued.action_data.bot_name == bot_name)?;
+        self.pending.remove(index)
+    }
+
+    /// Pending actions in the order they will run
+    pub fn pending(&self) -> Vec<PendingAction> {
+        self.pending.iter().map(QueuedAction::to_pending).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
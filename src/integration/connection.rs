@@ -0,0 +1,144 @@
+use crate::communication::bus::Publisher;
+use crate::communication::types::{AuthResponse, Correlation, Event, PollingConfig};
+use crate::state::persistence::StoredServer;
+use crate::state::state_types::MainState;
+use std::sync::Arc;
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use super::iot_integration::{connect_with_timeout, IoTIntegration};
+use super::server_actor::ServerActor;
+
+/// Connects the integration to its IoT server and, when authentication
+/// succeeds, hands it over to a new server actor.
+#[instrument(name = "connect", skip_all, fields(
+    integration = integration.integration_type(),
+    user_id = integration.user_id(),
+))]
+pub async fn connect_and_begin_listening(
+    mut integration: Box<dyn IoTIntegration>,
+    polling: Option<PollingConfig>,
+    server_state: Arc<MainState>,
+    publisher: Publisher,
+    correlation: Correlation,
+) {
+    let connect_res = connect_with_timeout(integration.as_mut()).await;
+    let user_id = integration.user_id();
+    let outside_name = integration.outside_name();
+    // If authentication is successfull we should
+    // relay that information directly to the message
+    // broker channel
+    match connect_res {
+        Ok(inbound_frames) => {
+            let new_server_id = Uuid::new_v4().to_string();
+            info!(server_id = %new_server_id, "authenticated");
+            //insert our new server
+            ServerActor::spawn(
+                new_server_id.clone(),
+                integration,
+                polling,
+                Some(inbound_frames),
+                server_state.clone(),
+                publisher.clone(),
+            )
+            .await;
+            //let the consumer know, that this request
+            //was successful and we are awaiting commands
+            //for the newly added server
+            send_auth_response(
+                user_id,
+                true,
+                Some(new_server_id),
+                &publisher,
+                Some(outside_name),
+                &correlation,
+            );
+        }
+        Err(err) => {
+            warn!(%err, "failed to connect");
+            send_auth_response(user_id, false, None, &publisher, None, &correlation);
+        }
+    }
+}
+
+/// Brings back every server that was connected before a restart under
+/// its old server_id. Servers that can't be reached right away are
+/// left to the actor's reconnect loop.
+pub async fn restore_servers(server_state: Arc<MainState>, publisher: Publisher) {
+    let stored_servers = match server_state.store.all() {
+        Ok(stored_servers) => stored_servers,
+        Err(err) => {
+            error!(%err, "failed to read persisted servers");
+            return;
+        }
+    };
+    for stored in stored_servers {
+        tokio::task::spawn(restore_server(
+            stored,
+            server_state.clone(),
+            publisher.clone(),
+        ));
+    }
+}
+
+async fn restore_server(stored: StoredServer, server_state: Arc<MainState>, publisher: Publisher) {
+    let StoredServer {
+        server_id,
+        integration_type,
+        credentials,
+        polling,
+    } = stored;
+    let mut integration = match server_state
+        .integrations
+        .create(&integration_type, &credentials)
+    {
+        Ok(integration) => integration,
+        Err(err) => {
+            error!(%server_id, %err, "dropping persisted server");
+            server_state.store.remove(&server_id).unwrap_or_default();
+            return;
+        }
+    };
+    let inbound_frames = connect_with_timeout(integration.as_mut()).await.ok();
+    // the general server may have disconnected it while we were
+    // connecting, spawning now would persist and register it again
+    if !server_state.store.contains(&server_id) {
+        info!(%server_id, "disconnected while restoring");
+        integration.disconnect().await;
+        return;
+    }
+    let restored = AuthResponse {
+        user_id: integration.user_id(),
+        passed_auth: inbound_frames.is_some(),
+        server_id: Some(server_id.clone()),
+        outside_name: Some(integration.outside_name()),
+    };
+    info!(%server_id, passed_auth = restored.passed_auth, "restoring server");
+    ServerActor::spawn(
+        server_id.clone(),
+        integration,
+        polling,
+        inbound_frames,
+        server_state,
+        publisher.clone(),
+    )
+    .await;
+    publisher.publish_event(Some(server_id), Event::Restored(restored));
+}
+
+fn send_auth_response(
+    user_id: i32,
+    passed: bool,
+    server_id: Option<String>,
+    publisher: &Publisher,
+    outside_name: Option<String>,
+    correlation: &Correlation,
+) {
+    let auth_response = AuthResponse {
+        user_id,
+        passed_auth: passed,
+        server_id: server_id.clone(),
+        outside_name,
+    };
+    publisher.reply(server_id, Event::AuthResponse(auth_response), correlation);
+}
@@ -0,0 +1,60 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::communication::types::Device;
+
+/// Empty device, filled in with `set_on_off` and `set_reading`
+pub fn new_device(id: &str, name: &str, device_type: &str) -> Device {
+    Device {
+        id: id.to_owned(),
+        name: name.to_owned(),
+        device_type: device_type.to_owned(),
+        capabilities: Vec::new(),
+        state: Default::default(),
+        units: Default::default(),
+        last_seen: now_millis(),
+    }
+}
+
+/// Records that the device can be switched on and off, and whether it is on
+pub fn set_on_off(device: &mut Device, on: bool) {
+    set_reading(device, "on_off", Value::Bool(on));
+}
+
+/// Records a reading such as brightness or temperature, with its unit if well known
+pub fn set_reading(device: &mut Device, key: &str, value: Value) {
+    if !device
+        .capabilities
+        .iter()
+        .any(|capability| capability == key)
+    {
+        device.capabilities.push(key.to_owned());
+    }
+    if let Some(unit) = unit_for(key) {
+        device.units.insert(key.to_owned(), unit.to_owned());
+    }
+    device.state.insert(key.to_owned(), value);
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Units of readings whose name alone tells us the unit
+fn unit_for(key: &str) -> Option<&'static str> {
+    match key {
+        "temperature" | "local_temperature" => Some("°C"),
+        "humidity" | "battery" => Some("%"),
+        "power" => Some("W"),
+        "voltage" => Some("V"),
+        "current" => Some("A"),
+        "energy" => Some("kWh"),
+        "illuminance_lux" => Some("lx"),
+        "pressure" => Some("hPa"),
+        _ => None,
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::communication::secret::credentials_json;
+use crate::communication::types::{ActionResult, Device, HOIActionData, HomeAssistantCredentials};
+use crate::state::metrics::METRICS;
+use async_trait::async_trait;
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::{stream::SplitStream, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+};
+use tracing::warn;
+use zeroize::Zeroizing;
+
+use super::device::{new_device, set_on_off, set_reading};
+use super::iot_integration::{websocket_frames, InboundEvent, InboundFrames, IoTIntegration};
+
+/// Requests we sent to Home Assistant and are waiting on a result for
+enum PendingRequest {
+    Subscribe,
+    States,
+    Action(HOIActionData),
+}
+
+/// Home Assistant integration, talks to the HA websocket API
+/// and maps every entity into a device.
+pub struct HomeAssistant {
+    credentials: HomeAssistantCredentials,
+    tx: Option<UnboundedSender<Message>>,
+    /// Every HA command carries an id that is echoed on its result
+    next_id: u64,
+    pending: HashMap<u64, PendingRequest>,
+    /// Latest state of every entity, kept up to date by state_changed
+    /// events so we can always publish a full snapshot.
+    entities: BTreeMap<String, Device>,
+}
+
+impl HomeAssistant {
+    pub fn new(credentials: HomeAssistantCredentials) -> Self {
+        Self {
+            credentials,
+            tx: None,
+            next_id: 1,
+            pending: HashMap::new(),
+            entities: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_connect_data(credentials: &[u8]) -> anyhow::Result<Box<dyn IoTIntegration>> {
+        let credentials: HomeAssistantCredentials = serde_json::from_slice(credentials)?;
+        Ok(Box::new(Self::new(credentials)))
+    }
+
+    /// Sends a command, filling in the id HA expects
+    fn send_command(&mut self, mut command: Value, pending: PendingRequest) -> anyhow::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        command["id"] = json!(id);
+        match &self.tx {
+            Some(tx) if !tx.is_closed() => tx.unbounded_send(Message::Text(command.to_string()))?,
+            _ => anyhow::bail!("not connected"),
+        }
+        self.pending.insert(id, pending);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<Device> {
+        self.entities.values().cloned().collect()
+    }
+
+    fn handle_result(&mut self, response: &Value) -> InboundEvent {
+        let pending = match response["id"]
+            .as_u64()
+            .and_then(|id| self.pending.remove(&id))
+        {
+            Some(pending) => pending,
+            None => return InboundEvent::Ignored,
+        };
+        let success = response["success"].as_bool().unwrap_or(false);
+        match pending {
+            PendingRequest::Subscribe => {
+                if !success {
+                    warn!("failed to subscribe to home assistant state changes");
+                }
+                InboundEvent::Handled
+            }
+            PendingRequest::States => {
+                if let Some(states) = response["result"].as_array() {
+                    self.entities = states
+                        .iter()
+                        .filter_map(entity_to_device)
+                        .map(|entity| (entity.id.clone(), entity))
+                        .collect();
+                }
+                InboundEvent::PassiveData(self.snapshot())
+            }
+            PendingRequest::Action(action_data) => InboundEvent::ActionResponse(ActionResult {
+                device_id: action_data.bot_name,
+                action: action_data.action,
+                success,
+            }),
+        }
+    }
+
+    fn handle_event(&mut self, response: &Value) -> InboundEvent {
+        let event = &response["event"];
+        if event["event_type"] != "state_changed" {
+            return InboundEvent::Ignored;
+        }
+        let entity_id = event["data"]["entity_id"].as_str().unwrap_or_default();
+        match entity_to_device(&event["data"]["new_state"]) {
+            Some(entity) => {
+                self.entities.insert(entity.id.clone(), entity);
+            }
+            // new_state is null when the entity was removed
+            None => {
+                self.entities.remove(entity_id);
+            }
+        }
+        InboundEvent::StateUpdate(self.snapshot())
+    }
+}
+
+/// Reads the next message and returns its "type" field
+async fn next_message_type(
+    read: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+) -> Option<String> {
+    let msg = read.next().await?.ok()?;
+    let msg: Value = serde_json::from_str(&msg.to_string()).ok()?;
+    msg["type"].as_str().map(str::to_owned)
+}
+
+/// Maps a HA state object into a device, sensors report their state
+/// as a "value" reading and everything else as on/off.
+fn entity_to_device(state: &Value) -> Option<Device> {
+    let entity_id = state["entity_id"].as_str()?;
+    let (domain, _) = entity_id.split_once('.')?;
+    let attributes = &state["attributes"];
+    let name = attributes["friendly_name"].as_str().unwrap_or(entity_id);
+    let mut device = new_device(entity_id, name, domain);
+    let value = state["state"].as_str().unwrap_or_default();
+    match domain {
+        "sensor" | "number" | "input_number" => {
+            let reading = value
+                .parse::<f64>()
+                .map(|number| json!(number))
+                .unwrap_or_else(|_| json!(value));
+            set_reading(&mut device, "value", reading);
+            if let Some(unit) = attributes["unit_of_measurement"].as_str() {
+                device.units.insert("value".to_owned(), unit.to_owned());
+            }
+        }
+        _ => set_on_off(
+            &mut device,
+            !matches!(value, "off" | "unavailable" | "unknown" | "closed"),
+        ),
+    }
+    if let Some(brightness) = attributes.get("brightness").filter(|b| b.is_number()) {
+        set_reading(&mut device, "brightness", brightness.clone());
+    }
+    Some(device)
+}
+
+#[async_trait]
+impl IoTIntegration for HomeAssistant {
+    fn integration_type(&self) -> &'static str {
+        "home_assistant"
+    }
+
+    fn user_id(&self) -> i32 {
+        self.credentials.user_id
+    }
+
+    fn outside_name(&self) -> String {
+        self.credentials.outside_name.clone()
+    }
+
+    fn credentials(&self) -> Zeroizing<Vec<u8>> {
+        credentials_json(&self.credentials)
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<InboundFrames> {
+        let url = url::Url::parse(&self.credentials.connection_str)?;
+        let (tx, rx) = futures_channel::mpsc::unbounded();
+        let (ws_stream, _) = connect_async(url).await?;
+        let (write, mut read) = ws_stream.split();
+        tokio::task::spawn(rx.map(Ok).forward(write));
+
+        // HA always starts with auth_required and answers our
+        // token with either auth_ok or auth_invalid
+        if next_message_type(&mut read).await.as_deref() != Some("auth_required") {
+            anyhow::bail!("expected auth_required from home assistant");
+        }
+        let auth =
+            json!({ "type": "auth", "access_token": self.credentials.access_token.expose() });
+        tx.unbounded_send(Message::Text(auth.to_string()))?;
+        if next_message_type(&mut read).await.as_deref() != Some("auth_ok") {
+            METRICS
+                .auth_failures
+                .with_label_values(&[self.integration_type()])
+                .inc();
+            anyhow::bail!("authentication failed");
+        }
+
+        self.tx = Some(tx.clone());
+        self.next_id = 1;
+        self.pending.clear();
+        self.send_command(
+            json!({ "type": "subscribe_events", "event_type": "state_changed" }),
+            PendingRequest::Subscribe,
+        )?;
+        Ok(websocket_frames(read, tx))
+    }
+
+    async fn request_passive_data(&mut self) -> anyhow::Result<()> {
+        self.send_command(json!({ "type": "get_states" }), PendingRequest::States)
+    }
+
+    /// `bot_name` is the entity id and `action` the service to call on
+    /// its domain, e.g. light.kitchen + turn_on calls light.turn_on
+    async fn execute_action(&mut self, action_data: HOIActionData) -> anyhow::Result<()> {
+        let domain = match action_data.bot_name.split_once('.') {
+            Some((domain, _)) => domain.to_owned(),
+            None => anyhow::bail!("{} is not an entity id", action_data.bot_name),
+        };
+        let command = json!({
+            "type": "call_service",
+            "domain": domain,
+            "service": action_data.action,
+            "target": { "entity_id": action_data.bot_name },
+        });
+        self.send_command(command, PendingRequest::Action(action_data))
+    }
+
+    async fn handle_inbound_frame(&mut self, frame: String) -> InboundEvent {
+        let response: Value = match serde_json::from_str(&frame) {
+            Ok(response) => response,
+            Err(_) => return InboundEvent::Ignored,
+        };
+        match response["type"].as_str() {
+            Some("result") => self.handle_result(&response),
+            Some("event") => self.handle_event(&response),
+            _ => InboundEvent::Ignored,
+        }
+    }
+
+    async fn disconnect(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            tx.unbounded_send(Message::Close(None)).unwrap_or_default();
+            tx.close_channel();
+        }
+    }
+}
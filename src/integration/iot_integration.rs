@@ -0,0 +1,105 @@
+use crate::communication::types::{ActionResult, Device, HOIActionData};
+use async_trait::async_trait;
+use futures_channel::mpsc::UnboundedSender;
+use futures_util::{
+    future,
+    stream::{self, BoxStream, SplitStream},
+    StreamExt,
+};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
+use zeroize::Zeroizing;
+
+/// How long connecting and authenticating with an IoT server may
+/// take before the attempt counts as failed
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Every frame an IoT server sends us after authentication,
+/// already converted to text so the integration can parse it.
+pub type InboundFrames = BoxStream<'static, String>;
+
+/// What the core should do with a frame after the integration
+/// has looked at it.
+pub enum InboundEvent {
+    /// Every device of the server, answering a passive data request
+    PassiveData(Vec<Device>),
+    /// Every device of the server after the IoT server pushed a change
+    /// on its own, this doesn't finish any request we have in progress
+    StateUpdate(Vec<Device>),
+    /// The result of an action we requested earlier
+    ActionResponse(ActionResult),
+    /// The integration already dealt with the frame (e.g. admin auth)
+    Handled,
+    /// Nothing the main server cares about
+    Ignored,
+}
+
+/// Abstraction over a single connection to an IoT server.
+///
+/// Each supported IoT server type implements this trait so the
+/// router and the connection tasks never have to know which
+/// protocol is being spoken underneath.
+#[async_trait]
+pub trait IoTIntegration: Send + Sync {
+    /// The key this integration is registered under, e.g. "hoi"
+    fn integration_type(&self) -> &'static str;
+
+    /// The general server user that requested this connection
+    fn user_id(&self) -> i32;
+
+    /// The name the user gave this server on the general server
+    fn outside_name(&self) -> String;
+
+    /// Credentials as JSON, encrypted and persisted so the connection
+    /// can be restored through the registry after a restart
+    fn credentials(&self) -> Zeroizing<Vec<u8>>;
+
+    /// Connects and authenticates with the IoT server, returning
+    /// the stream of frames it sends us afterwards.
+    async fn connect(&mut self) -> anyhow::Result<InboundFrames>;
+
+    /// Asks the IoT server for the current state of every device
+    async fn request_passive_data(&mut self) -> anyhow::Result<()>;
+
+    /// Executes a single action against a device
+    async fn execute_action(&mut self, action_data: HOIActionData) -> anyhow::Result<()>;
+
+    /// Handles one frame coming from the IoT server
+    async fn handle_inbound_frame(&mut self, frame: String) -> InboundEvent;
+
+    /// Forwards a relation request (add/remove) to the IoT server,
+    /// integrations without relations can keep the default.
+    async fn relation_request(&mut self, _category: String, _data: String) -> anyhow::Result<()> {
+        anyhow::bail!("{} does not support relations", self.integration_type())
+    }
+
+    /// Closes the connection to the IoT server
+    async fn disconnect(&mut self);
+}
+
+/// Connects the integration, giving up after [`CONNECT_TIMEOUT`] so an
+/// IoT server that never finishes the handshake can't hang the caller.
+pub async fn connect_with_timeout(
+    integration: &mut dyn IoTIntegration,
+) -> anyhow::Result<InboundFrames> {
+    tokio::time::timeout(CONNECT_TIMEOUT, integration.connect())
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out connecting"))?
+}
+
+/// The text of every message read from a websocket. Once the socket
+/// ends `tx` is closed, so nothing more can be sent into a connection
+/// that is gone.
+pub fn websocket_frames(
+    read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    tx: UnboundedSender<Message>,
+) -> InboundFrames {
+    let closed = stream::once(async move {
+        tx.close_channel();
+        None
+    });
+    read.filter_map(|message| future::ready(message.ok().map(|msg| msg.to_string())))
+        .chain(closed.filter_map(future::ready))
+        .boxed()
+}
@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::communication::secret::credentials_json;
+use crate::communication::types::{
+    ActionResult, Device, HOIActionData, MqttConvention, MqttCredentials,
+};
+use crate::state::metrics::METRICS;
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use rumqttc::{AsyncClient, ConnectionError, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{self, UnboundedSender, WeakUnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::warn;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use super::device::{new_device, now_millis, set_on_off, set_reading};
+use super::iot_integration::{InboundEvent, InboundFrames, IoTIntegration};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Frames handed to `handle_inbound_frame`, MQTT has no request/response
+/// so passive data and action results are frames we feed ourselves.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MqttFrame {
+    Publish { topic: String, payload: String },
+    Snapshot,
+    ActionSent(HOIActionData),
+}
+
+/// MQTT integration for Zigbee2MQTT and Tasmota style devices
+pub struct Mqtt {
+    credentials: MqttCredentials,
+    client: Option<AsyncClient>,
+    event_loop_task: Option<JoinHandle<()>>,
+    /// Used to feed ourselves the frames MQTT has no equivalent for,
+    /// weak so the frames end as soon as the event loop task stops.
+    frames_tx: Option<WeakUnboundedSender<String>>,
+    /// Latest state of every device we saw a message for
+    devices: BTreeMap<String, Device>,
+}
+
+impl Mqtt {
+    pub fn new(credentials: MqttCredentials) -> Self {
+        Self {
+            credentials,
+            client: None,
+            event_loop_task: None,
+            frames_tx: None,
+            devices: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_connect_data(credentials: &[u8]) -> anyhow::Result<Box<dyn IoTIntegration>> {
+        let credentials: MqttCredentials = serde_json::from_slice(credentials)?;
+        Ok(Box::new(Self::new(credentials)))
+    }
+
+    fn base_topic(&self) -> &str {
+        self.credentials
+            .base_topic
+            .as_deref()
+            .unwrap_or("zigbee2mqtt")
+    }
+
+    fn state_topics(&self) -> Vec<String> {
+        if let Some(topics) = &self.credentials.state_topics {
+            return topics.clone();
+        }
+        match self.credentials.convention {
+            MqttConvention::Zigbee2mqtt => vec![format!("{}/+", self.base_topic())],
+            MqttConvention::Tasmota => vec![
+                "tele/+/STATE".to_owned(),
+                "stat/+/POWER".to_owned(),
+                "stat/+/RESULT".to_owned(),
+            ],
+        }
+    }
+
+    fn command_topic(&self, device: &str) -> String {
+        match (&self.credentials.command_topic, self.credentials.convention) {
+            (Some(template), _) => template.replace("{device}", device),
+            (None, MqttConvention::Zigbee2mqtt) => format!("{}/{}/set", self.base_topic(), device),
+            (None, MqttConvention::Tasmota) => format!("cmnd/{}/POWER", device),
+        }
+    }
+
+    /// Zigbee2MQTT expects JSON while Tasmota takes the raw command
+    fn command_payload(&self, action: &str) -> String {
+        if serde_json::from_str::<Value>(action).is_ok_and(|value| value.is_object()) {
+            return action.to_owned();
+        }
+        match self.credentials.convention {
+            MqttConvention::Zigbee2mqtt => json!({ "state": action }).to_string(),
+            MqttConvention::Tasmota => action.to_owned(),
+        }
+    }
+
+    fn feed(&self, frame: MqttFrame) -> anyhow::Result<()> {
+        match self.frames_tx.as_ref().and_then(|tx| tx.upgrade()) {
+            Some(tx) => Ok(tx.send(serde_json::to_string(&frame)?)?),
+            None => anyhow::bail!("not connected"),
+        }
+    }
+
+    fn snapshot(&self) -> Vec<Device> {
+        self.devices.values().cloned().collect()
+    }
+
+    /// Updates the device a state message is about, returns false if
+    /// the topic isn't a device state for our convention.
+    fn update_device(&mut self, topic: &str, payload: &str) -> bool {
+        let levels: Vec<&str> = topic.split('/').collect();
+        // the device, whether it's on if the message says so
+        // and every other reading in the message
+        let (name, on, readings) = match self.credentials.convention {
+            MqttConvention::Zigbee2mqtt => {
+                let base_levels = self.base_topic().split('/').count();
+                // skips bridge/ messages and sub topics like <device>/availability
+                if levels.len() != base_levels + 1 || levels[base_levels] == "bridge" {
+                    return false;
+                }
+                let state = match serde_json::from_str::<Value>(payload) {
+                    Ok(Value::Object(state)) => state,
+                    _ => return false,
+                };
+                let readings = state
+                    .iter()
+                    .filter(|(key, value)| {
+                        *key != "state" && (value.is_number() || value.is_boolean())
+                    })
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                let on = state.get("state").map(power_is_on);
+                (levels[base_levels], on, readings)
+            }
+            MqttConvention::Tasmota => match levels.as_slice() {
+                ["stat", device, "POWER"] => (*device, Some(power_is_on(&json!(payload))), vec![]),
+                ["tele", device, "STATE"] | ["stat", device, "RESULT"] => {
+                    let state: Value = serde_json::from_str(payload).unwrap_or_default();
+                    if state["POWER"].is_null() {
+                        return false;
+                    }
+                    (*device, Some(power_is_on(&state["POWER"])), vec![])
+                }
+                _ => return false,
+            },
+        };
+        let device_type = match self.credentials.convention {
+            MqttConvention::Zigbee2mqtt => "zigbee2mqtt",
+            MqttConvention::Tasmota => "tasmota",
+        };
+        let device = self
+            .devices
+            .entry(name.to_owned())
+            .or_insert_with(|| new_device(name, name, device_type));
+        if let Some(on) = on {
+            set_on_off(device, on);
+        }
+        for (key, value) in readings {
+            set_reading(device, &key, value);
+        }
+        device.last_seen = now_millis();
+        true
+    }
+}
+
+fn power_is_on(state: &Value) -> bool {
+    state
+        .as_str()
+        .is_some_and(|state| state.eq_ignore_ascii_case("on"))
+}
+
+/// Polls the MQTT event loop until the broker answers our connect
+async fn wait_for_connack(event_loop: &mut EventLoop) -> Result<(), ConnectionError> {
+    loop {
+        match event_loop.poll().await? {
+            Event::Incoming(Packet::ConnAck(_)) => return Ok(()),
+            _ => continue,
+        }
+    }
+}
+
+/// Drives the event loop, forwarding every publish as a frame. Stops at
+/// the first connection error so the server actor can reconnect us.
+async fn forward_publishes(mut event_loop: EventLoop, frames_tx: UnboundedSender<String>) {
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let frame = MqttFrame::Publish {
+                    topic: publish.topic,
+                    payload: String::from_utf8_lossy(&publish.payload).into_owned(),
+                };
+                if frames_tx
+                    .send(serde_json::to_string(&frame).unwrap())
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(err) => {
+                warn!(%err, "mqtt connection lost");
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl IoTIntegration for Mqtt {
+    fn integration_type(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn user_id(&self) -> i32 {
+        self.credentials.user_id
+    }
+
+    fn outside_name(&self) -> String {
+        self.credentials.outside_name.clone()
+    }
+
+    fn credentials(&self) -> Zeroizing<Vec<u8>> {
+        credentials_json(&self.credentials)
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<InboundFrames> {
+        let client_id = format!("bors-{}", Uuid::new_v4());
+        let mut options = MqttOptions::new(
+            client_id,
+            self.credentials.host.clone(),
+            self.credentials.port,
+        );
+        options.set_keep_alive(KEEP_ALIVE);
+        if let (Some(username), Some(password)) =
+            (&self.credentials.username, &self.credentials.password)
+        {
+            options.set_credentials(username.clone(), password.expose());
+        }
+        let (client, mut event_loop) = AsyncClient::new(options, 64);
+        match tokio::time::timeout(CONNECT_TIMEOUT, wait_for_connack(&mut event_loop)).await? {
+            Ok(()) => {}
+            Err(err @ ConnectionError::ConnectionRefused(_)) => {
+                METRICS
+                    .auth_failures
+                    .with_label_values(&[self.integration_type()])
+                    .inc();
+                return Err(err.into());
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let (frames_tx, frames_rx) = mpsc::unbounded_channel::<String>();
+        for topic in self.state_topics() {
+            client.subscribe(topic, QoS::AtLeastOnce).await?;
+        }
+        if let Some(task) = self.event_loop_task.take() {
+            task.abort();
+        }
+        self.frames_tx = Some(frames_tx.downgrade());
+        self.event_loop_task = Some(tokio::task::spawn(forward_publishes(event_loop, frames_tx)));
+        self.client = Some(client);
+        Ok(stream::unfold(frames_rx, |mut frames_rx| async move {
+            let frame = frames_rx.recv().await?;
+            Some((frame, frames_rx))
+        })
+        .boxed())
+    }
+
+    /// Devices push their state on their own, so the cached
+    /// state is always the freshest we can offer.
+    async fn request_passive_data(&mut self) -> anyhow::Result<()> {
+        self.feed(MqttFrame::Snapshot)
+    }
+
+    async fn execute_action(&mut self, action_data: HOIActionData) -> anyhow::Result<()> {
+        let client = match &self.client {
+            Some(client) => client,
+            None => anyhow::bail!("not connected"),
+        };
+        let topic = self.command_topic(&action_data.bot_name);
+        let payload = self.command_payload(&action_data.action);
+        client
+            .publish(topic, QoS::AtLeastOnce, false, payload)
+            .await?;
+        self.feed(MqttFrame::ActionSent(action_data))
+    }
+
+    async fn handle_inbound_frame(&mut self, frame: String) -> InboundEvent {
+        match serde_json::from_str(&frame) {
+            Ok(MqttFrame::Publish { topic, payload }) => {
+                if self.update_device(&topic, &payload) {
+                    InboundEvent::StateUpdate(self.snapshot())
+                } else {
+                    InboundEvent::Ignored
+                }
+            }
+            Ok(MqttFrame::Snapshot) => InboundEvent::PassiveData(self.snapshot()),
+            // MQTT has no acknowledgement from the device itself
+            Ok(MqttFrame::ActionSent(action_data)) => InboundEvent::ActionResponse(ActionResult {
+                device_id: action_data.bot_name,
+                action: action_data.action,
+                success: true,
+            }),
+            Err(_) => InboundEvent::Ignored,
+        }
+    }
+
+    async fn disconnect(&mut self) {
+        if let Some(client) = self.client.take() {
+            client.disconnect().await.unwrap_or_default();
+        }
+        if let Some(task) = self.event_loop_task.take() {
+            task.abort();
+        }
+        self.frames_tx = None;
+    }
+}
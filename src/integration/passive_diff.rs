@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+
+use crate::communication::types::{Device, Event};
+
+/// What to publish for a passive snapshot
+pub enum PassiveUpdate {
+    /// The whole snapshot, we had nothing to compare it to
+    Full,
+    /// One event per device that was added, removed or changed
+    Changes(Vec<Event>),
+}
+
+/// Keeps the last passive snapshot of a server so every new one
+/// can be published as the device events it amounts to.
+#[derive(Default)]
+pub struct PassiveDiff {
+    devices: Option<BTreeMap<String, Device>>,
+}
+
+impl PassiveDiff {
+    /// The first snapshot has to be sent in full. A device that was
+    /// only seen again (last_seen moved on) doesn't count as changed.
+    pub fn update(&mut self, snapshot: &[Device]) -> PassiveUpdate {
+        let current: BTreeMap<String, Device> = snapshot
+            .iter()
+            .map(|device| (device.id.clone(), device.clone()))
+            .collect();
+        let previous = match self.devices.replace(current.clone()) {
+            Some(previous) => previous,
+            None => return PassiveUpdate::Full,
+        };
+        let mut events = Vec::new();
+        for (id, device) in &current {
+            match previous.get(id) {
+                None => events.push(Event::DeviceAdded(Box::new(device.clone()))),
+                Some(old) if !old.same_state(device) => {
+                    events.push(Event::DeviceStateChanged(Box::new(device.clone())))
+                }
+                Some(_) => {}
+            }
+        }
+        for (id, old) in previous {
+            if !current.contains_key(&id) {
+                events.push(Event::DeviceRemoved(Box::new(old)));
+            }
+        }
+        PassiveUpdate::Changes(events)
+    }
+
+    /// Forgets the last snapshot so the next one is sent in full
+    pub fn reset(&mut self) {
+        self.devices = None;
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::communication::types::Device;
+
+/// Declarative filtering and reshaping of devices before anything
+/// about them is published, configured under `[filters.<key>]`.
+///
+/// Steps run in field order: allow/deny, thresholds, unit
+/// conversion, projection and finally renaming.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pipeline {
+    /// Only devices of these types are kept, all if empty
+    pub allow_types: Vec<String>,
+    pub deny_types: Vec<String>,
+    /// Only devices whose id matches one of these patterns are
+    /// kept, all if empty. `*` matches any run of characters.
+    pub allow_ids: Vec<String>,
+    pub deny_ids: Vec<String>,
+    /// Like the id patterns, matched against the device name instead
+    pub allow_names: Vec<String>,
+    pub deny_names: Vec<String>,
+    /// Drops readings that are out of bounds, the device itself is kept
+    /// so a reading crossing a bound is a state change, not the device
+    /// disappearing and coming back
+    pub thresholds: HashMap<String, Threshold>,
+    /// Target unit per reading, e.g. temperature = "°F"
+    pub convert: HashMap<String, String>,
+    /// Only these readings are kept, all if empty
+    pub fields: Vec<String>,
+    /// New name per reading, e.g. brightness = "level"
+    pub rename: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Threshold {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl Pipeline {
+    pub fn apply(&self, devices: Vec<Device>) -> Vec<Device> {
+        devices
+            .into_iter()
+            .filter(|device| self.allows(device))
+            .map(|device| self.reshape(device))
+            .collect()
+    }
+
+    fn allows(&self, device: &Device) -> bool {
+        let type_allowed = (self.allow_types.is_empty()
+            || self.allow_types.contains(&device.device_type))
+            && !self.deny_types.contains(&device.device_type);
+        type_allowed
+            && patterns_allow(&self.allow_ids, &self.deny_ids, &device.id)
+            && patterns_allow(&self.allow_names, &self.deny_names, &device.name)
+    }
+
+    fn reshape(&self, mut device: Device) -> Device {
+        for (key, threshold) in &self.thresholds {
+            let Some(value) = device.state.get(key).and_then(Value::as_f64) else {
+                continue;
+            };
+            let within = threshold.min.is_none_or(|min| value >= min)
+                && threshold.max.is_none_or(|max| value <= max);
+            if !within {
+                device.state.remove(key);
+                device.units.remove(key);
+            }
+        }
+        for (key, to) in &self.convert {
+            let (Some(value), Some(from)) = (
+                device.state.get(key).and_then(Value::as_f64),
+                device.units.get(key),
+            ) else {
+                continue;
+            };
+            if let Some(converted) = convert_unit(value, from, to) {
+                device.state.insert(key.clone(), json!(converted));
+                device.units.insert(key.clone(), to.clone());
+            }
+        }
+        if !self.fields.is_empty() {
+            device.capabilities.retain(|key| self.fields.contains(key));
+            device.state.retain(|key, _| self.fields.contains(key));
+            device.units.retain(|key, _| self.fields.contains(key));
+        }
+        if !self.rename.is_empty() {
+            let renamed = |key: &String| self.rename.get(key).unwrap_or(key).clone();
+            device.capabilities = device.capabilities.iter().map(renamed).collect();
+            device.state = rename_keys(device.state, renamed);
+            device.units = rename_keys(device.units, renamed);
+        }
+        device
+    }
+}
+
+fn rename_keys<V>(
+    map: BTreeMap<String, V>,
+    renamed: impl Fn(&String) -> String,
+) -> BTreeMap<String, V> {
+    map.into_iter()
+        .map(|(key, value)| (renamed(&key), value))
+        .collect()
+}
+
+fn convert_unit(value: f64, from: &str, to: &str) -> Option<f64> {
+    let converted = match (from, to) {
+        (from, to) if from == to => value,
+        ("°C", "°F") => value * 9.0 / 5.0 + 32.0,
+        ("°F", "°C") => (value - 32.0) * 5.0 / 9.0,
+        ("W", "kW") | ("Wh", "kWh") => value / 1000.0,
+        ("kW", "W") | ("kWh", "Wh") => value * 1000.0,
+        _ => return None,
+    };
+    // keeps float noise like 69.80000000000001 out of the payload
+    Some((converted * 1000.0).round() / 1000.0)
+}
+
+fn patterns_allow(allow: &[String], deny: &[String], name: &str) -> bool {
+    (allow.is_empty() || allow.iter().any(|p| matches_pattern(p, name)))
+        && !deny.iter().any(|p| matches_pattern(p, name))
+}
+
+/// Matches `name` against a pattern where `*` stands for any run of characters
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no `*` at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
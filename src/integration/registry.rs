@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+
+use super::{
+    home_assistant::HomeAssistant, house_of_iot::HouseOfIoT, iot_integration::IoTIntegration,
+    mqtt::Mqtt,
+};
+
+/// Builds a new (not yet connected) integration from the credentials
+/// JSON of a connect command or of a persisted server.
+pub type IntegrationFactory = fn(&[u8]) -> anyhow::Result<Box<dyn IoTIntegration>>;
+
+/// Maps integration types (`ConnectData::integration_type`) to
+/// the factory that knows how to build them.
+pub struct IntegrationRegistry {
+    factories: HashMap<String, IntegrationFactory>,
+}
+
+impl IntegrationRegistry {
+    pub fn new() -> Self {
+        Self {
+            factories: HashMap::new(),
+        }
+    }
+
+    /// Registry containing every integration shipped with Bors,
+    /// new integrations only need to be added here.
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register("hoi", HouseOfIoT::from_connect_data);
+        registry.register("home_assistant", HomeAssistant::from_connect_data);
+        registry.register("mqtt", Mqtt::from_connect_data);
+        registry
+    }
+
+    pub fn register(&mut self, integration_type: &str, factory: IntegrationFactory) {
+        self.factories.insert(integration_type.to_owned(), factory);
+    }
+
+    pub fn create(
+        &self,
+        integration_type: &str,
+        credentials: &[u8],
+    ) -> anyhow::Result<Box<dyn IoTIntegration>> {
+        match self.factories.get(integration_type) {
+            Some(factory) => factory(credentials),
+            None => anyhow::bail!("unknown integration type: {}", integration_type),
+        }
+    }
+}
+
+impl Default for IntegrationRegistry {
+    fn default() -> Self {
+        Self::with_defaults()
+    }
+}
@@ -0,0 +1,855 @@
+use crate::communication::backoff::Backoff;
+use crate::communication::bus::Publisher;
+use crate::communication::types::{
+    ActionResult, ActionTimeout, CommandRejected, Correlation, Device, Event, HOIActionData,
+    PendingAction, PollingConfig,
+};
+use crate::state::config::Polling;
+use crate::state::metrics::METRICS;
+use crate::state::persistence::StoredServer;
+use crate::state::state_types::MainState;
+use futures_util::StreamExt;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use super::action_queue::{ActionQueue, Pushed, QueuedAction};
+use super::device::now_millis;
+use super::iot_integration::{connect_with_timeout, InboundEvent, InboundFrames, IoTIntegration};
+use super::passive_diff::{PassiveDiff, PassiveUpdate};
+use super::pipeline::Pipeline;
+
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+const RECONNECT_MAX_ATTEMPTS: u32 = 10;
+/// How long the IoT server gets to answer before we give up on a request
+const ACTION_TIMEOUT: Duration = Duration::from_secs(10);
+const PASSIVE_DATA_TIMEOUT: Duration = Duration::from_secs(15);
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+/// Actions past this many pending ones are rejected
+const MAX_QUEUED_ACTIONS: usize = 32;
+/// An actor stuck awaiting its IoT server for longer than this isn't asked
+const INSPECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Commands the router can send to a running server actor
+#[derive(Debug)]
+pub enum ServerCommand {
+    QueueAction(HOIActionData, Correlation),
+    CancelAction(String, Correlation),
+    ListActions(Correlation),
+    /// Answered with relation_request_made, or an error if the
+    /// integration couldn't pass it on
+    Relation {
+        category: String,
+        data: String,
+        correlation: Correlation,
+    },
+    Disconnect,
+    /// Answers with what the actor is up to, for the admin API
+    Inspect(oneshot::Sender<ServerStatus>),
+    /// Requests passive data as soon as nothing is in flight,
+    /// publishing it as a full snapshot
+    RefreshPassive,
+    /// Sends the queued actions back to back instead of
+    /// one per action interval, until the queue is empty
+    DrainQueue,
+    /// Cancels every queued action, answering with them
+    ClearQueue(oneshot::Sender<Vec<PendingAction>>),
+    /// Bors is shutting down, finish the action in flight and close
+    /// the connection but keep the server persisted for the restart
+    Shutdown,
+}
+
+/// Cheap to clone handle used to talk to the actor that
+/// owns a connected IoT server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    pub server_id: String,
+    pub integration_type: &'static str,
+    pub user_id: i32,
+    tx: UnboundedSender<ServerCommand>,
+}
+
+impl ServerHandle {
+    /// Returns false if the actor has already stopped
+    pub fn send(&self, command: ServerCommand) -> bool {
+        self.tx.send(command).is_ok()
+    }
+
+    /// None if the actor stopped or didn't answer in time
+    pub async fn status(&self) -> Option<ServerStatus> {
+        let (reply, status) = oneshot::channel();
+        if !self.send(ServerCommand::Inspect(reply)) {
+            return None;
+        }
+        tokio::time::timeout(INSPECT_TIMEOUT, status)
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// Like [`ServerHandle::status`], but an actor that didn't answer in
+    /// time is still reported, with what the handle knows about it
+    pub async fn report(&self) -> ServerStatus {
+        match self.status().await {
+            Some(status) => status,
+            None => ServerStatus {
+                server_id: self.server_id.clone(),
+                integration_type: self.integration_type,
+                user_id: self.user_id,
+                responding: false,
+                connected: false,
+                last_passive_data: None,
+                last_action_result: None,
+                queue: Vec::new(),
+                action_in_flight: None,
+                action_in_progress: false,
+                passive_in_progress: false,
+                draining: false,
+            },
+        }
+    }
+
+    /// Cancels every queued action, None if the actor didn't answer in time
+    pub async fn clear_queue(&self) -> Option<Vec<PendingAction>> {
+        let (reply, cleared) = oneshot::channel();
+        if !self.send(ServerCommand::ClearQueue(reply)) {
+            return None;
+        }
+        tokio::time::timeout(INSPECT_TIMEOUT, cleared)
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// Resolves once the actor has stopped
+    pub async fn stopped(&self) {
+        self.tx.closed().await
+    }
+}
+
+/// Snapshot of a server actor, as shown by the admin API and
+/// trimmed down by the status endpoint
+#[derive(Serialize, Debug)]
+pub struct ServerStatus {
+    pub server_id: String,
+    pub integration_type: &'static str,
+    pub user_id: i32,
+    /// False if the actor didn't answer in time, e.g. while it waits on
+    /// a connect attempt, everything below is unknown then
+    pub responding: bool,
+    /// False while reconnecting to the IoT server
+    pub connected: bool,
+    /// Unix time in milliseconds of the last passive data or pushed state
+    pub last_passive_data: Option<u64>,
+    pub last_action_result: Option<ActionResult>,
+    /// In the order the actions will run
+    pub queue: Vec<PendingAction>,
+    pub action_in_flight: Option<PendingAction>,
+    pub action_in_progress: bool,
+    pub passive_in_progress: bool,
+    pub draining: bool,
+}
+
+/// Owns everything related to a single connected IoT server,
+/// so servers never have to wait on each other.
+pub struct ServerActor {
+    server_id: String,
+    integration: Box<dyn IoTIntegration>,
+    action_execution_queue: ActionQueue,
+    /// Keeping track of the action in progress to never
+    /// have two actions running at once which won't work
+    /// with some IoT servers especially HOI.
+    action_in_progress: bool,
+    /// The action in progress, its correlation is echoed on its response
+    action_in_flight: Option<QueuedAction>,
+    /// How many times the action in flight was sent
+    action_attempts: u32,
+    action_deadline: Option<Instant>,
+    /// Keeping track of the passive data request in progress,
+    /// HOI can't have an action + passive data in progress.
+    passive_in_progress: bool,
+    passive_deadline: Option<Instant>,
+    /// When the passive request in flight was sent, for its latency
+    passive_requested_at: Option<Instant>,
+    polling: Polling,
+    /// Current passive interval, backs off while passive data stays
+    /// the same and snaps back once it changes or an action ran
+    passive_interval: Duration,
+    next_passive_at: Instant,
+    /// When the main server last got fresh passive data, used
+    /// to enforce `polling.max_staleness`
+    last_passive_at: Instant,
+    passive_diff: PassiveDiff,
+    last_full_snapshot_at: Instant,
+    /// Filters and reshapes devices before anything is published
+    pipeline: Pipeline,
+    /// Set on shutdown, nothing new is sent to the IoT server
+    /// and the actor stops once no action is in flight
+    shutting_down: bool,
+    /// Set by the admin API, see `ServerCommand::DrainQueue`
+    draining: bool,
+    /// What the status endpoints report
+    connected: bool,
+    last_passive_data: Option<u64>,
+    last_action_result: Option<ActionResult>,
+    publisher: Publisher,
+}
+
+impl ServerActor {
+    /// Spawns the actor for an integration and registers the handle used
+    /// to reach it. Without inbound frames (the integration isn't connected
+    /// yet) the actor starts by reconnecting.
+    pub async fn spawn(
+        server_id: String,
+        integration: Box<dyn IoTIntegration>,
+        polling: Option<PollingConfig>,
+        inbound_frames: Option<InboundFrames>,
+        server_state: Arc<MainState>,
+        publisher: Publisher,
+    ) {
+        let resolved = server_state
+            .config
+            .polling_for(integration.integration_type(), polling.as_ref());
+        let pipeline = server_state
+            .config
+            .pipeline_for(&server_id, integration.integration_type());
+        // not a child of the command that connected us, the actor outlives it
+        let span = info_span!(
+            parent: None,
+            "server",
+            server_id = %server_id,
+            user_id = integration.user_id(),
+            integration = integration.integration_type(),
+        );
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handle = ServerHandle {
+            server_id: server_id.clone(),
+            integration_type: integration.integration_type(),
+            user_id: integration.user_id(),
+            tx,
+        };
+        let stored = StoredServer {
+            server_id: server_id.clone(),
+            integration_type: integration.integration_type().to_owned(),
+            credentials: integration.credentials(),
+            polling,
+        };
+        let actor = Self {
+            server_id,
+            integration,
+            action_execution_queue: ActionQueue::new(MAX_QUEUED_ACTIONS),
+            action_in_progress: false,
+            action_in_flight: None,
+            action_attempts: 0,
+            action_deadline: None,
+            passive_in_progress: false,
+            passive_deadline: None,
+            passive_requested_at: None,
+            polling: resolved,
+            passive_interval: resolved.passive_interval,
+            next_passive_at: Instant::now() + resolved.passive_interval,
+            last_passive_at: Instant::now(),
+            passive_diff: PassiveDiff::default(),
+            last_full_snapshot_at: Instant::now(),
+            pipeline,
+            shutting_down: false,
+            draining: false,
+            connected: inbound_frames.is_some(),
+            last_passive_data: None,
+            last_action_result: None,
+            publisher,
+        };
+        server_state.insert_server(handle, stored).await;
+        tokio::task::spawn(actor.run(rx, inbound_frames, server_state).instrument(span));
+    }
+
+    async fn run(
+        mut self,
+        mut commands: UnboundedReceiver<ServerCommand>,
+        inbound_frames: Option<InboundFrames>,
+        server_state: Arc<MainState>,
+    ) {
+        let inbound_frames = match inbound_frames {
+            Some(frames) => Some(frames),
+            None => self.reconnect(&mut commands).await,
+        };
+        if let Some(inbound_frames) = inbound_frames {
+            self.run_connected(&mut commands, inbound_frames).await;
+        }
+        METRICS
+            .queue_depth
+            .remove_label_values(&[&self.server_id])
+            .unwrap_or_default();
+        if self.shutting_down {
+            self.integration.disconnect().await;
+            self.publish(Event::Disconnected);
+            server_state.unregister_server(&self.server_id).await;
+        } else {
+            server_state.remove_server(&self.server_id).await;
+        }
+        info!("stopped");
+    }
+
+    async fn run_connected(
+        &mut self,
+        commands: &mut UnboundedReceiver<ServerCommand>,
+        mut inbound_frames: InboundFrames,
+    ) {
+        let mut action_tick = ticker(self.polling.action_interval);
+        let mut watchdog_tick = ticker(WATCHDOG_INTERVAL);
+        loop {
+            tokio::select! {
+                command = commands.recv() => {
+                    if !self.handle_command(command).await {
+                        break;
+                    }
+                },
+                frame = inbound_frames.next() => match frame {
+                    Some(frame) => self.route_message(frame).await,
+                    // the IoT server dropped us, keep the same server_id
+                    // and try to get the connection back
+                    None => match self.reconnect(commands).await {
+                        Some(frames) => inbound_frames = frames,
+                        None => break,
+                    },
+                },
+                _ = action_tick.tick() => self.execute_next_action().await,
+                _ = tokio::time::sleep_until(self.next_passive_at) => self.request_passive_data().await,
+                _ = watchdog_tick.tick() => self.time_out_stuck_requests().await,
+            }
+            if self.shutting_down && !self.action_in_progress {
+                break;
+            }
+        }
+    }
+
+    /// Returns false once the actor should stop
+    async fn handle_command(&mut self, command: Option<ServerCommand>) -> bool {
+        match command {
+            Some(ServerCommand::QueueAction(action_data, correlation)) => {
+                let queued = QueuedAction {
+                    action_data,
+                    correlation,
+                };
+                match self.action_execution_queue.push(queued) {
+                    Pushed::Queued => {}
+                    Pushed::Replaced(replaced) => self.cancelled(replaced),
+                    Pushed::Full(rejected) => self.reject(
+                        &rejected.correlation,
+                        "action",
+                        "action queue is full".to_owned(),
+                    ),
+                }
+                self.queue_changed();
+            }
+            Some(ServerCommand::CancelAction(bot_name, correlation)) => {
+                match self.action_execution_queue.cancel(&bot_name) {
+                    Some(cancelled) => self.cancelled(cancelled),
+                    None => self.reject(
+                        &correlation,
+                        "cancel_action",
+                        format!("no pending action for {}", bot_name),
+                    ),
+                }
+                self.queue_changed();
+            }
+            Some(ServerCommand::ListActions(correlation)) => {
+                self.publisher.reply(
+                    Some(self.server_id.clone()),
+                    Event::PendingActions(self.action_execution_queue.pending()),
+                    &correlation,
+                );
+            }
+            Some(ServerCommand::Relation {
+                category,
+                correlation,
+                ..
+            }) if !self.connected => {
+                self.reject(&correlation, &category, "server is reconnecting".to_owned())
+            }
+            Some(ServerCommand::Relation {
+                category,
+                data,
+                correlation,
+            }) => match self
+                .integration
+                .relation_request(category.clone(), data)
+                .await
+            {
+                Ok(()) => self.publisher.reply(
+                    Some(self.server_id.clone()),
+                    Event::RelationRequestMade(category),
+                    &correlation,
+                ),
+                Err(err) => self.reject(&correlation, &category, err.to_string()),
+            },
+            Some(ServerCommand::Inspect(reply)) => {
+                reply.send(self.status()).unwrap_or_default();
+            }
+            Some(ServerCommand::RefreshPassive) => {
+                // whatever answers next goes out in full
+                self.passive_diff.reset();
+                self.request_passive_data().await;
+            }
+            Some(ServerCommand::DrainQueue) => {
+                self.draining = true;
+                self.execute_next_action().await;
+            }
+            Some(ServerCommand::ClearQueue(reply)) => {
+                let mut cleared = Vec::new();
+                while let Some(queued) = self.action_execution_queue.pop() {
+                    cleared.push(queued.to_pending());
+                    self.cancelled(queued);
+                }
+                self.queue_changed();
+                reply.send(cleared).unwrap_or_default();
+            }
+            Some(ServerCommand::Shutdown) => {
+                self.shutting_down = true;
+                while let Some(queued) = self.action_execution_queue.pop() {
+                    self.cancelled(queued);
+                }
+                self.queue_changed();
+            }
+            Some(ServerCommand::Disconnect) | None => {
+                self.integration.disconnect().await;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Re-authenticates with the stored credentials using exponential
+    /// backoff, while still accepting commands so actions queued in the
+    /// meantime run once we are back. Returns None if we gave up or were
+    /// told to disconnect.
+    async fn reconnect(
+        &mut self,
+        commands: &mut UnboundedReceiver<ServerCommand>,
+    ) -> Option<InboundFrames> {
+        self.connected = false;
+        // whatever was in flight died with the old connection
+        self.abandon_action_in_flight();
+        self.clear_old_in_progress();
+        let mut backoff = Backoff::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY);
+        while backoff.attempt() < RECONNECT_MAX_ATTEMPTS {
+            let delay = backoff.next_delay();
+            self.publish(Event::Reconnecting(backoff.attempt()));
+            info!(attempt = backoff.attempt(), ?delay, "reconnecting");
+            METRICS
+                .server_reconnects
+                .with_label_values(&[self.integration.integration_type()])
+                .inc();
+            let retry_at = tokio::time::sleep(delay);
+            tokio::pin!(retry_at);
+            loop {
+                tokio::select! {
+                    command = commands.recv() => {
+                        if !self.handle_command(command).await || self.shutting_down {
+                            return None;
+                        }
+                    },
+                    _ = &mut retry_at => break,
+                }
+            }
+            // only a disconnect or shutdown cuts the attempt short, anything
+            // else waits for it, which connect_with_timeout keeps bounded
+            let mut deferred = Vec::new();
+            let attempt = {
+                let connect = connect_with_timeout(self.integration.as_mut());
+                tokio::pin!(connect);
+                loop {
+                    tokio::select! {
+                        res = &mut connect => break Some(res),
+                        command = commands.recv() => {
+                            let stops = matches!(
+                                command,
+                                None | Some(ServerCommand::Disconnect | ServerCommand::Shutdown)
+                            );
+                            deferred.push(command);
+                            if stops {
+                                break None;
+                            }
+                        },
+                    }
+                }
+            };
+            let frames = match attempt {
+                Some(Ok(frames)) => {
+                    // we may have missed changes while we were gone
+                    self.passive_diff.reset();
+                    info!("reconnected");
+                    self.connected = true;
+                    self.publish(Event::Reconnected);
+                    Some(frames)
+                }
+                Some(Err(err)) => {
+                    debug!(%err, "reconnect attempt failed");
+                    None
+                }
+                None => None,
+            };
+            for command in deferred {
+                if !self.handle_command(command).await || self.shutting_down {
+                    return None;
+                }
+            }
+            if frames.is_some() {
+                return frames;
+            }
+        }
+        warn!("gave up reconnecting");
+        self.publish(Event::Lost);
+        None
+    }
+
+    async fn execute_next_action(&mut self) {
+        // Only request action if nothing is blocking us from requesting
+        // Things that could block us from requesting:
+        // 1. Being in the middle of a passive request
+        // 2. Being in the middle of an action request
+        // 3. The main server's data being stale, passive data goes first
+        if self.action_in_progress
+            || self.passive_in_progress
+            || self.shutting_down
+            || !self.connected
+            || self.passive_stale()
+        {
+            return;
+        }
+        //get the most recent queued action and execute
+        match self.action_execution_queue.pop() {
+            Some(queued) => {
+                self.queue_changed();
+                self.action_attempts = 0;
+                self.send_action(queued).await;
+            }
+            None => self.draining = false,
+        }
+    }
+
+    async fn send_action(&mut self, queued: QueuedAction) {
+        debug!(
+            bot_name = %queued.action_data.bot_name,
+            action = %queued.action_data.action,
+            correlation_id = queued.correlation.correlation_id.as_deref(),
+            attempt = self.action_attempts + 1,
+            "sending action",
+        );
+        self.action_in_progress = true;
+        self.action_attempts += 1;
+        self.action_deadline = Some(Instant::now() + ACTION_TIMEOUT);
+        self.action_in_flight = Some(queued.clone());
+        if let Err(err) = self
+            .integration
+            .execute_action(queued.action_data.clone())
+            .await
+        {
+            // never reached the IoT server, so there is no response to wait for
+            warn!(%err, "failed to send action");
+            self.action_in_progress = false;
+            self.action_deadline = None;
+            self.action_in_flight = None;
+            self.count_action("failed");
+            let result = ActionResult {
+                device_id: queued.action_data.bot_name,
+                action: queued.action_data.action,
+                success: false,
+            };
+            self.last_action_result = Some(result.clone());
+            self.publisher.reply(
+                Some(self.server_id.clone()),
+                Event::ActionResponse(result),
+                &queued.correlation,
+            );
+        }
+    }
+
+    /// Resets requests the IoT server never answered so the server
+    /// doesn't stay blocked forever, telling the main server about it.
+    async fn time_out_stuck_requests(&mut self) {
+        let now = Instant::now();
+        if self.action_in_progress && self.action_deadline.is_some_and(|at| at <= now) {
+            self.action_in_progress = false;
+            self.action_deadline = None;
+            if let Some(queued) = self.action_in_flight.take() {
+                self.count_action("timed_out");
+                let retrying =
+                    self.action_attempts <= self.polling.action_retries && !self.shutting_down;
+                warn!(
+                    bot_name = %queued.action_data.bot_name,
+                    correlation_id = queued.correlation.correlation_id.as_deref(),
+                    attempt = self.action_attempts,
+                    retrying,
+                    "action timed out",
+                );
+                let timeout = ActionTimeout {
+                    bot_name: queued.action_data.bot_name.clone(),
+                    action: queued.action_data.action.clone(),
+                    attempt: self.action_attempts,
+                    retrying,
+                };
+                self.publisher.reply(
+                    Some(self.server_id.clone()),
+                    Event::ActionTimeout(timeout),
+                    &queued.correlation,
+                );
+                if retrying {
+                    self.send_action(queued).await;
+                } else if self.draining {
+                    self.execute_next_action().await;
+                }
+            }
+        }
+        if self.passive_in_progress && self.passive_deadline.is_some_and(|at| at <= now) {
+            // the next passive tick simply asks again
+            self.passive_in_progress = false;
+            self.passive_deadline = None;
+            warn!("passive data request timed out");
+            self.publish(Event::PassiveTimeout);
+        }
+    }
+
+    /// The connection died with the action in flight, it may or may not
+    /// have run so it isn't sent again, its sender gets a timeout
+    fn abandon_action_in_flight(&mut self) {
+        let Some(queued) = self.action_in_flight.take() else {
+            return;
+        };
+        self.count_action("timed_out");
+        warn!(
+            bot_name = %queued.action_data.bot_name,
+            correlation_id = queued.correlation.correlation_id.as_deref(),
+            "connection lost with an action in flight",
+        );
+        let timeout = ActionTimeout {
+            bot_name: queued.action_data.bot_name,
+            action: queued.action_data.action,
+            attempt: self.action_attempts,
+            retrying: false,
+        };
+        self.publisher.reply(
+            Some(self.server_id.clone()),
+            Event::ActionTimeout(timeout),
+            &queued.correlation,
+        );
+    }
+
+    async fn request_passive_data(&mut self) {
+        if !self.connected {
+            // commands are still handled while reconnecting, ask
+            // again as soon as there is a connection to ask on
+            self.next_passive_at = Instant::now();
+            return;
+        }
+        self.schedule_passive_data(self.passive_interval);
+        // Only request passive data if nothing is blocking us from requesting
+        // Things that could block us from requesting:
+        // 1. Being in the middle of a passive request
+        // 2. Being in the middle of an action request, HOI would take
+        //    passive_data for the action's argument or admin password
+        if self.shutting_down {
+            return;
+        }
+        if self.passive_in_progress || self.action_in_progress {
+            // stale data is scheduled for right now, so wait until the
+            // watchdog has timed out the request in progress instead. An
+            // answer reschedules passive data anyway
+            if let Some(deadline) = self.passive_deadline.or(self.action_deadline) {
+                let timed_out_at = deadline.max(Instant::now()) + WATCHDOG_INTERVAL;
+                self.next_passive_at = self.next_passive_at.max(timed_out_at);
+            }
+            return;
+        }
+        //set the in progress flag
+        self.passive_in_progress = true;
+        self.passive_deadline = Some(Instant::now() + PASSIVE_DATA_TIMEOUT);
+        self.passive_requested_at = Some(Instant::now());
+        if self.integration.request_passive_data().await.is_err() {
+            warn!("issue sending for passive data");
+        }
+    }
+
+    /// Stale passive data is requested before the next action is sent
+    fn passive_stale(&self) -> bool {
+        self.last_passive_at.elapsed() >= self.polling.max_staleness
+    }
+
+    /// Never schedules past the point the main server's data turns stale
+    fn schedule_passive_data(&mut self, after: Duration) {
+        let now = Instant::now();
+        let stale_at = self.last_passive_at + self.polling.max_staleness;
+        self.next_passive_at = (now + after).min(stale_at.max(now));
+    }
+
+    /// Backs the passive interval off while the data stays the same
+    fn passive_data_received(&mut self, changed: bool) {
+        self.passive_interval = if changed {
+            self.polling.passive_interval
+        } else {
+            (self.passive_interval * 2).min(self.polling.max_passive_interval)
+        };
+        self.last_passive_at = Instant::now();
+        self.schedule_passive_data(self.passive_interval);
+    }
+
+    /// Lets the integration interpret a frame from the IoT server
+    /// and relays anything relevant to the main server.
+    async fn route_message(&mut self, frame: String) {
+        match self.integration.handle_inbound_frame(frame).await {
+            InboundEvent::PassiveData(devices) => {
+                if let Some(requested_at) = self.passive_requested_at.take() {
+                    METRICS
+                        .passive_poll_latency
+                        .with_label_values(&[self.integration.integration_type()])
+                        .observe(requested_at.elapsed().as_secs_f64());
+                }
+                self.passive_in_progress = false;
+                self.passive_deadline = None;
+                self.last_passive_data = Some(now_millis());
+                let changed = self.publish_passive_data(devices);
+                self.passive_data_received(changed);
+            }
+            InboundEvent::StateUpdate(devices) => {
+                // pushed state is as fresh as it gets, but says nothing
+                // about how often it changes so the interval stays as is
+                self.last_passive_at = Instant::now();
+                self.last_passive_data = Some(now_millis());
+                self.publish_passive_data(devices);
+            }
+            InboundEvent::ActionResponse(result) => {
+                self.action_in_progress = false;
+                self.action_deadline = None;
+                self.count_action(if result.success { "executed" } else { "failed" });
+                self.last_action_result = Some(result.clone());
+                // the action most likely changed something, pick it up soon
+                self.passive_interval = self.polling.passive_interval;
+                self.schedule_passive_data(self.polling.action_interval);
+                let correlation = self
+                    .action_in_flight
+                    .take()
+                    .map(|queued| queued.correlation)
+                    .unwrap_or_default();
+                self.publisher.reply(
+                    Some(self.server_id.clone()),
+                    Event::ActionResponse(result),
+                    &correlation,
+                );
+                if self.draining {
+                    self.execute_next_action().await;
+                }
+            }
+            InboundEvent::Handled | InboundEvent::Ignored => {}
+        }
+    }
+
+    /// Publishes only what changed since the last snapshot, unless
+    /// a full snapshot is needed or due. Returns whether anything changed.
+    fn publish_passive_data(&mut self, devices: Vec<Device>) -> bool {
+        let devices = self.pipeline.apply(devices);
+        let update = self.passive_diff.update(&devices);
+        let full_snapshot_due = self
+            .polling
+            .full_snapshot_interval
+            .is_some_and(|interval| self.last_full_snapshot_at.elapsed() >= interval);
+        match update {
+            PassiveUpdate::Changes(events) if !full_snapshot_due => {
+                let changed = !events.is_empty();
+                for event in events {
+                    self.publish(event);
+                }
+                changed
+            }
+            update => {
+                self.last_full_snapshot_at = Instant::now();
+                self.publish(Event::PassiveData(devices));
+                match update {
+                    PassiveUpdate::Changes(events) => !events.is_empty(),
+                    PassiveUpdate::Full => true,
+                }
+            }
+        }
+    }
+
+    fn status(&self) -> ServerStatus {
+        ServerStatus {
+            server_id: self.server_id.clone(),
+            integration_type: self.integration.integration_type(),
+            user_id: self.integration.user_id(),
+            responding: true,
+            connected: self.connected,
+            last_passive_data: self.last_passive_data,
+            last_action_result: self.last_action_result.clone(),
+            queue: self.action_execution_queue.pending(),
+            action_in_flight: self.action_in_flight.as_ref().map(QueuedAction::to_pending),
+            action_in_progress: self.action_in_progress,
+            passive_in_progress: self.passive_in_progress,
+            draining: self.draining,
+        }
+    }
+
+    fn queue_changed(&self) {
+        METRICS
+            .queue_depth
+            .with_label_values(&[&self.server_id])
+            .set(self.action_execution_queue.len() as i64);
+    }
+
+    fn count_action(&self, outcome: &str) {
+        METRICS
+            .actions
+            .with_label_values(&[self.integration.integration_type(), outcome])
+            .inc();
+    }
+
+    /// Tells whoever queued the action that it won't run
+    fn cancelled(&self, cancelled: QueuedAction) {
+        self.publisher.reply(
+            Some(self.server_id.clone()),
+            Event::ActionCancelled(Box::new(cancelled.to_pending())),
+            &cancelled.correlation,
+        );
+    }
+
+    fn reject(&self, correlation: &Correlation, category: &str, reason: String) {
+        self.publisher.reply(
+            Some(self.server_id.clone()),
+            Event::Error(CommandRejected {
+                category: Some(category.to_owned()),
+                reason,
+            }),
+            correlation,
+        );
+    }
+
+    /// Relays an event about this server to the main server
+    fn publish(&self, event: Event) {
+        self.publisher
+            .publish_event(Some(self.server_id.clone()), event);
+    }
+
+    /// Used to set the in-progess flags to false, to allow the next action/passive data request
+    /// to be executed, since only one can happen at a time.
+    /// These must be false since neither can be true at the same time
+    /// and after every response(from the iot server) then each passive data/action cycle is over.
+    ///
+    /// You can read more about how we manage action/passive data requests in the docs.
+    fn clear_old_in_progress(&mut self) {
+        self.action_in_progress = false;
+        self.passive_in_progress = false;
+        self.action_deadline = None;
+        self.passive_deadline = None;
+    }
+}
+
+/// Interval whose first tick happens one period from now
+fn ticker(period: Duration) -> Interval {
+    let mut ticker = interval_at(Instant::now() + period, period);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker
+}
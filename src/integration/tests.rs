@@ -0,0 +1,1498 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
+use zeroize::Zeroizing;
+
+use crate::communication::bus::{OutboundMessage, Publisher};
+use crate::communication::secret::credentials_json;
+use crate::communication::types::{
+    ActionPriority, Correlation, Device, HOIActionData, HomeAssistantCredentials,
+    HouseOfIoTCredentials, MqttConvention, MqttCredentials, PollingConfig,
+};
+use crate::communication::{protocol, router};
+use crate::state::config::Config;
+use crate::state::metrics::METRICS;
+use crate::state::persistence::{ServerStore, StoredServer};
+use crate::state::state_types::MainState;
+
+use super::action_queue::{ActionQueue, Pushed, QueuedAction};
+use super::connection;
+use super::device::{new_device, set_on_off, set_reading};
+use super::home_assistant::HomeAssistant;
+use super::house_of_iot::HouseOfIoT;
+use super::iot_integration::{InboundEvent, InboundFrames, IoTIntegration};
+use super::mqtt::Mqtt;
+use super::passive_diff::{PassiveDiff, PassiveUpdate};
+use super::server_actor::{ServerActor, ServerCommand};
+
+/// Minimal stand in for the Home Assistant websocket API, accepts
+/// `token` and answers get_states/call_service from `states`.
+async fn spawn_mock_home_assistant(token: &'static str, states: Value) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let states = states.clone();
+            tokio::task::spawn(async move {
+                let mut ws = accept_async(stream).await.unwrap();
+                let send = |value: Value| Message::Text(value.to_string());
+                ws.send(send(json!({"type": "auth_required"})))
+                    .await
+                    .unwrap();
+                let auth: Value = match ws.next().await {
+                    Some(Ok(msg)) => serde_json::from_str(&msg.to_string()).unwrap(),
+                    _ => return,
+                };
+                if auth["access_token"] != token {
+                    ws.send(send(
+                        json!({"type": "auth_invalid", "message": "bad token"}),
+                    ))
+                    .await
+                    .unwrap_or_default();
+                    return;
+                }
+                ws.send(send(json!({"type": "auth_ok"}))).await.unwrap();
+                let mut subscription = None;
+                while let Some(Ok(msg)) = ws.next().await {
+                    let command: Value = match serde_json::from_str(&msg.to_string()) {
+                        Ok(command) => command,
+                        Err(_) => continue,
+                    };
+                    let id = command["id"].clone();
+                    let result = match command["type"].as_str() {
+                        Some("subscribe_events") => {
+                            subscription = Some(id.clone());
+                            Value::Null
+                        }
+                        Some("get_states") => states.clone(),
+                        Some("call_service") => Value::Null,
+                        _ => continue,
+                    };
+                    ws.send(send(json!({
+                        "id": id, "type": "result", "success": true, "result": result
+                    })))
+                    .await
+                    .unwrap();
+                    if let (Some("call_service"), Some(subscription)) =
+                        (command["type"].as_str(), &subscription)
+                    {
+                        let entity_id = command["target"]["entity_id"].clone();
+                        let state = if command["service"] == "turn_off" {
+                            "off"
+                        } else {
+                            "on"
+                        };
+                        ws.send(send(json!({
+                            "id": subscription,
+                            "type": "event",
+                            "event": {
+                                "event_type": "state_changed",
+                                "data": {
+                                    "entity_id": entity_id,
+                                    "new_state": {"entity_id": entity_id, "state": state},
+                                },
+                            },
+                        })))
+                        .await
+                        .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    format!("ws://{}/api/websocket", addr)
+}
+
+pub(crate) fn home_assistant(url: String, token: &str) -> HomeAssistant {
+    HomeAssistant::new(HomeAssistantCredentials {
+        connection_str: url,
+        access_token: token.into(),
+        outside_name: "home".to_owned(),
+        user_id: 1,
+    })
+}
+
+/// Feeds frames to the integration until it produces something
+/// the main server would care about.
+async fn next_event(
+    integration: &mut dyn IoTIntegration,
+    frames: &mut InboundFrames,
+) -> InboundEvent {
+    loop {
+        let frame = tokio::time::timeout(Duration::from_secs(5), frames.next())
+            .await
+            .expect("timed out waiting for a frame")
+            .expect("connection closed");
+        match integration.handle_inbound_frame(frame).await {
+            InboundEvent::Handled | InboundEvent::Ignored => continue,
+            event => return event,
+        }
+    }
+}
+
+fn device<'a>(devices: &'a [Device], id: &str) -> &'a Device {
+    devices.iter().find(|device| device.id == id).unwrap()
+}
+
+#[tokio::test]
+async fn home_assistant_maps_states_to_devices() {
+    let url = spawn_mock_home_assistant(
+        "token",
+        json!([
+            {"entity_id": "light.kitchen", "state": "on", "attributes": {"brightness": 120}},
+            {"entity_id": "switch.fan", "state": "off"},
+            {
+                "entity_id": "sensor.temperature",
+                "state": "21.5",
+                "attributes": {"unit_of_measurement": "°C", "friendly_name": "Temperature"},
+            },
+        ]),
+    )
+    .await;
+    let mut integration = home_assistant(url, "token");
+    let mut frames = integration.connect().await.unwrap();
+    integration.request_passive_data().await.unwrap();
+
+    match next_event(&mut integration, &mut frames).await {
+        InboundEvent::PassiveData(devices) => {
+            let kitchen = device(&devices, "light.kitchen");
+            assert_eq!(kitchen.device_type, "light");
+            assert_eq!(kitchen.capabilities, ["on_off", "brightness"]);
+            assert_eq!(kitchen.state["on_off"], true);
+            assert_eq!(kitchen.state["brightness"], 120);
+            assert_eq!(device(&devices, "switch.fan").state["on_off"], false);
+            let temperature = device(&devices, "sensor.temperature");
+            assert_eq!(temperature.name, "Temperature");
+            assert_eq!(temperature.state["value"], 21.5);
+            assert_eq!(temperature.units["value"], "°C");
+        }
+        _ => panic!("expected passive data"),
+    }
+}
+
+#[tokio::test]
+async fn home_assistant_rejects_invalid_token() {
+    let url = spawn_mock_home_assistant("token", json!([])).await;
+    let mut integration = home_assistant(url, "wrong");
+    assert!(integration.connect().await.is_err());
+    let auth_failures = METRICS.auth_failures.with_label_values(&["home_assistant"]);
+    assert!(auth_failures.get() >= 1);
+}
+
+#[tokio::test]
+async fn home_assistant_calls_service_and_follows_state_changes() {
+    let url = spawn_mock_home_assistant(
+        "token",
+        json!([{"entity_id": "switch.fan", "state": "off"}]),
+    )
+    .await;
+    let mut integration = home_assistant(url, "token");
+    let mut frames = integration.connect().await.unwrap();
+    integration.request_passive_data().await.unwrap();
+    next_event(&mut integration, &mut frames).await;
+
+    integration
+        .execute_action(HOIActionData {
+            bot_name: "switch.fan".to_owned(),
+            action: "turn_on".to_owned(),
+            priority: ActionPriority::Normal,
+        })
+        .await
+        .unwrap();
+    match next_event(&mut integration, &mut frames).await {
+        InboundEvent::ActionResponse(result) => {
+            assert_eq!(result.device_id, "switch.fan");
+            assert_eq!(result.action, "turn_on");
+            assert!(result.success);
+        }
+        _ => panic!("expected an action response"),
+    }
+    match next_event(&mut integration, &mut frames).await {
+        InboundEvent::StateUpdate(devices) => {
+            assert_eq!(device(&devices, "switch.fan").state["on_off"], true);
+        }
+        _ => panic!("expected a state update"),
+    }
+}
+
+/// Starts an in-process MQTT broker that only accepts user/password
+/// and returns the port it listens on once it accepts connections.
+fn spawn_mqtt_broker() -> u16 {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config: rumqttd::Config = serde_json::from_value(json!({
+        "id": 0,
+        "router": {
+            "max_connections": 100,
+            "max_outgoing_packet_count": 200,
+            "max_segment_size": 1048576,
+            "max_segment_count": 10,
+        },
+        "v4": {"1": {
+            "name": "v4-1",
+            "listen": format!("127.0.0.1:{}", port),
+            "next_connection_delay_ms": 1,
+            "connections": {
+                "connection_timeout_ms": 5000,
+                "max_payload_size": 20480,
+                "max_inflight_count": 100,
+                "auth": {"user": "password"},
+            },
+        }},
+    }))
+    .unwrap();
+    std::thread::spawn(move || rumqttd::Broker::new(config).start().unwrap());
+    for _ in 0..100 {
+        if std::net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    port
+}
+
+fn mqtt(port: u16, password: &str, convention: MqttConvention) -> Mqtt {
+    Mqtt::new(MqttCredentials {
+        host: "127.0.0.1".to_owned(),
+        port,
+        username: Some("user".to_owned()),
+        password: Some(password.into()),
+        convention,
+        base_topic: None,
+        state_topics: None,
+        command_topic: None,
+        outside_name: "mqtt".to_owned(),
+        user_id: 1,
+    })
+}
+
+/// Plays the part of the devices, returns every publish it receives
+async fn mqtt_device_client(
+    port: u16,
+    subscribe: &str,
+) -> (
+    rumqttc::AsyncClient,
+    tokio::sync::mpsc::UnboundedReceiver<rumqttc::Publish>,
+) {
+    let mut options = rumqttc::MqttOptions::new("devices", "127.0.0.1", port);
+    options.set_credentials("user", "password");
+    let (client, mut event_loop) = rumqttc::AsyncClient::new(options, 16);
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (subscribed_tx, subscribed_rx) = tokio::sync::oneshot::channel();
+    client
+        .subscribe(subscribe, rumqttc::QoS::AtLeastOnce)
+        .await
+        .unwrap();
+    tokio::task::spawn(async move {
+        let mut subscribed_tx = Some(subscribed_tx);
+        while let Ok(event) = event_loop.poll().await {
+            match event {
+                rumqttc::Event::Incoming(rumqttc::Packet::SubAck(_)) => {
+                    if let Some(subscribed_tx) = subscribed_tx.take() {
+                        subscribed_tx.send(()).unwrap();
+                    }
+                }
+                rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish)) => {
+                    tx.send(publish).unwrap_or_default();
+                }
+                _ => {}
+            }
+        }
+    });
+    subscribed_rx.await.unwrap();
+    (client, rx)
+}
+
+#[tokio::test]
+async fn mqtt_follows_zigbee2mqtt_device_state() {
+    let port = spawn_mqtt_broker();
+    let (devices, _) = mqtt_device_client(port, "unused").await;
+    let mut integration = mqtt(port, "password", MqttConvention::Zigbee2mqtt);
+    let mut frames = integration.connect().await.unwrap();
+
+    // retained like zigbee2mqtt does, so it reaches us even if our
+    // subscription is still in flight
+    for (topic, payload) in [
+        ("zigbee2mqtt/bridge/state", "online"),
+        ("zigbee2mqtt/lamp", r#"{"state":"ON","brightness":200}"#),
+    ] {
+        devices
+            .publish(topic, rumqttc::QoS::AtLeastOnce, true, payload)
+            .await
+            .unwrap();
+    }
+    match next_event(&mut integration, &mut frames).await {
+        InboundEvent::StateUpdate(devices) => {
+            assert_eq!(devices.len(), 1);
+            assert_eq!(device(&devices, "lamp").state["on_off"], true);
+            assert_eq!(device(&devices, "lamp").state["brightness"], 200);
+        }
+        _ => panic!("expected a state update"),
+    }
+
+    integration.request_passive_data().await.unwrap();
+    match next_event(&mut integration, &mut frames).await {
+        InboundEvent::PassiveData(devices) => {
+            assert_eq!(device(&devices, "lamp").device_type, "zigbee2mqtt");
+        }
+        _ => panic!("expected passive data"),
+    }
+}
+
+#[tokio::test]
+async fn mqtt_publishes_tasmota_commands() {
+    let port = spawn_mqtt_broker();
+    let (devices, mut commands) = mqtt_device_client(port, "cmnd/+/POWER").await;
+    let mut integration = mqtt(port, "password", MqttConvention::Tasmota);
+    let mut frames = integration.connect().await.unwrap();
+
+    integration
+        .execute_action(HOIActionData {
+            bot_name: "plug".to_owned(),
+            action: "ON".to_owned(),
+            priority: ActionPriority::Normal,
+        })
+        .await
+        .unwrap();
+    match next_event(&mut integration, &mut frames).await {
+        InboundEvent::ActionResponse(result) => {
+            assert_eq!(result.device_id, "plug");
+            assert!(result.success);
+        }
+        _ => panic!("expected an action response"),
+    }
+    let command = tokio::time::timeout(Duration::from_secs(5), commands.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(command.topic, "cmnd/plug/POWER");
+    assert_eq!(&command.payload[..], b"ON");
+
+    devices
+        .publish("stat/plug/POWER", rumqttc::QoS::AtLeastOnce, true, "ON")
+        .await
+        .unwrap();
+    match next_event(&mut integration, &mut frames).await {
+        InboundEvent::StateUpdate(devices) => {
+            assert_eq!(device(&devices, "plug").state["on_off"], true);
+        }
+        _ => panic!("expected a state update"),
+    }
+}
+
+#[tokio::test]
+async fn mqtt_rejects_bad_credentials() {
+    let port = spawn_mqtt_broker();
+    let mut integration = mqtt(port, "wrong", MqttConvention::Zigbee2mqtt);
+    assert!(integration.connect().await.is_err());
+}
+
+/// Stand in for an IoT server that accepts everything and never answers
+/// actions. Clones share their counters and connection, so a test can
+/// keep one to look at what the actor did or to drop the connection.
+#[derive(Clone, Default)]
+pub(crate) struct FakeIntegration {
+    /// Answers every passive request with the same empty snapshot
+    answers_passive: bool,
+    /// Never finishes connecting after the first time, like an IoT
+    /// server that accepts the socket but never answers the handshake
+    hangs_on_reconnect: bool,
+    /// Never finishes connecting, not even the first time
+    hangs_on_connect: bool,
+    /// Fails every action before it reaches the IoT server
+    fails_actions: bool,
+    pub(crate) actions: Arc<AtomicU32>,
+    passive_requests: Arc<AtomicU32>,
+    connects: Arc<AtomicU32>,
+    frames_tx: Arc<std::sync::Mutex<Option<tokio::sync::mpsc::UnboundedSender<String>>>>,
+}
+
+impl FakeIntegration {
+    fn answering_passive() -> Self {
+        Self {
+            answers_passive: true,
+            ..Default::default()
+        }
+    }
+
+    /// Ends the inbound frames, like the IoT server going away
+    fn drop_connection(&self) {
+        self.frames_tx.lock().unwrap().take();
+    }
+}
+
+#[async_trait::async_trait]
+impl IoTIntegration for FakeIntegration {
+    fn integration_type(&self) -> &'static str {
+        "fake"
+    }
+
+    fn user_id(&self) -> i32 {
+        1
+    }
+
+    fn outside_name(&self) -> String {
+        "fake".to_owned()
+    }
+
+    fn credentials(&self) -> Zeroizing<Vec<u8>> {
+        credentials_json(&json!({}))
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<InboundFrames> {
+        let reconnecting = self.connects.fetch_add(1, Ordering::SeqCst) > 0;
+        if self.hangs_on_connect || (reconnecting && self.hangs_on_reconnect) {
+            std::future::pending::<()>().await;
+        }
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        *self.frames_tx.lock().unwrap() = Some(tx);
+        Ok(frames_from(rx))
+    }
+
+    async fn request_passive_data(&mut self) -> anyhow::Result<()> {
+        self.passive_requests.fetch_add(1, Ordering::SeqCst);
+        if self.answers_passive {
+            if let Some(tx) = self.frames_tx.lock().unwrap().as_ref() {
+                tx.send("passive".to_owned())?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn execute_action(&mut self, _: HOIActionData) -> anyhow::Result<()> {
+        self.actions.fetch_add(1, Ordering::SeqCst);
+        if self.fails_actions {
+            anyhow::bail!("fake fails actions");
+        }
+        Ok(())
+    }
+
+    async fn handle_inbound_frame(&mut self, frame: String) -> InboundEvent {
+        match frame.as_str() {
+            "passive" => InboundEvent::PassiveData(vec![]),
+            _ => InboundEvent::Ignored,
+        }
+    }
+
+    async fn disconnect(&mut self) {}
+}
+
+/// Spawns a server actor around `integration` with the default
+/// config and `polling` overrides, returning the
+/// main state it's registered in and everything it publishes.
+pub(crate) async fn spawn_actor(
+    integration: impl IoTIntegration + 'static,
+    polling: Option<PollingConfig>,
+) -> (Arc<MainState>, UnboundedReceiver<OutboundMessage>) {
+    let state = Arc::new(MainState::new(
+        Config::default(),
+        ServerStore::temporary().unwrap(),
+    ));
+    let (publisher, outbound) = Publisher::new();
+    let mut integration: Box<dyn IoTIntegration> = Box::new(integration);
+    let frames = integration.connect().await.unwrap();
+    ServerActor::spawn(
+        "server".to_owned(),
+        integration,
+        polling,
+        Some(frames),
+        state.clone(),
+        publisher,
+    )
+    .await;
+    (state, outbound)
+}
+
+/// Waits for the next published event of `category`
+pub(crate) async fn next_published(
+    outbound: &mut UnboundedReceiver<OutboundMessage>,
+    category: &str,
+) -> OutboundMessage {
+    loop {
+        let message = outbound.recv().await.expect("publisher closed");
+        let event: Value = serde_json::from_slice(&message.data).unwrap();
+        if event["category"] == category {
+            return message;
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_action_times_out_and_is_retried() {
+    let fake = FakeIntegration::default();
+    let (state, mut outbound) = spawn_actor(fake.clone(), None).await;
+    let correlation = Correlation {
+        correlation_id: Some("abc".to_owned()),
+        reply_to: None,
+    };
+    state
+        .get_server("server")
+        .await
+        .unwrap()
+        .send(ServerCommand::QueueAction(
+            HOIActionData {
+                bot_name: "lamp".to_owned(),
+                action: "turn_on".to_owned(),
+                priority: ActionPriority::Normal,
+            },
+            correlation,
+        ));
+
+    for (attempt, retrying) in [(1, true), (2, false)] {
+        let message = next_published(&mut outbound, "action_timeout").await;
+        let event: Value = serde_json::from_slice(&message.data).unwrap();
+        assert_eq!(message.correlation_id.as_deref(), Some("abc"));
+        assert_eq!(event["data"]["bot_name"], "lamp");
+        assert_eq!(event["data"]["attempt"], attempt);
+        assert_eq!(event["data"]["retrying"], retrying);
+    }
+    assert_eq!(fake.actions.load(Ordering::SeqCst), 2);
+    // other tests time out "fake" actions too
+    let timed_out = METRICS.actions.with_label_values(&["fake", "timed_out"]);
+    assert!(timed_out.get() >= 2);
+    assert!(METRICS
+        .render()
+        .contains("bors_actions_total{integration=\"fake\",outcome=\"timed_out\"}"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn actions_that_fail_to_send_are_answered_right_away() {
+    let fake = FakeIntegration {
+        fails_actions: true,
+        ..Default::default()
+    };
+    let (state, mut outbound) = spawn_actor(fake.clone(), None).await;
+    let handle = state.get_server("server").await.unwrap();
+    let queued = queued("lamp", "on", ActionPriority::Normal, "failed-1");
+    handle.send(ServerCommand::QueueAction(
+        queued.action_data,
+        queued.correlation,
+    ));
+    let message = next_published(&mut outbound, "action_response").await;
+    assert_eq!(message.correlation_id.as_deref(), Some("failed-1"));
+    let response: Value = serde_json::from_slice(&message.data).unwrap();
+    assert_eq!(response["data"]["device_id"], "lamp");
+    assert_eq!(response["data"]["success"], false);
+    let status = handle.status().await.unwrap();
+    assert!(!status.action_in_progress);
+    assert!(status.action_in_flight.is_none());
+    // nothing left to time out or retry
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    while let Ok(message) = outbound.try_recv() {
+        let event: Value = serde_json::from_slice(&message.data).unwrap();
+        assert_ne!(event["category"], "action_timeout");
+    }
+    assert_eq!(fake.actions.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_passive_request_times_out() {
+    let (_state, mut outbound) = spawn_actor(FakeIntegration::default(), None).await;
+    next_published(&mut outbound, "passive_timeout").await;
+    // the flag was reset, so passive data keeps being requested
+    next_published(&mut outbound, "passive_timeout").await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn action_in_flight_times_out_when_the_connection_drops() {
+    let fake = FakeIntegration::default();
+    let (state, mut outbound) = spawn_actor(fake.clone(), None).await;
+    let handle = state.get_server("server").await.unwrap();
+    let queued = queued("lamp", "on", ActionPriority::Normal, "lost-1");
+    handle.send(ServerCommand::QueueAction(
+        queued.action_data,
+        queued.correlation,
+    ));
+    while fake.actions.load(Ordering::SeqCst) == 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    fake.drop_connection();
+    let message = next_published(&mut outbound, "action_timeout").await;
+    assert_eq!(message.correlation_id.as_deref(), Some("lost-1"));
+    let timeout: Value = serde_json::from_slice(&message.data).unwrap();
+    assert_eq!(timeout["data"]["retrying"], false);
+    next_published(&mut outbound, "reconnected").await;
+    let status = handle.status().await.unwrap();
+    assert!(status.action_in_flight.is_none());
+    assert!(!status.action_in_progress);
+    assert_eq!(fake.actions.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn hung_reconnects_time_out_and_still_take_commands() {
+    let fake = FakeIntegration {
+        hangs_on_reconnect: true,
+        ..Default::default()
+    };
+    let (state, mut outbound) = spawn_actor(fake.clone(), None).await;
+    let handle = state.get_server("server").await.unwrap();
+    fake.drop_connection();
+    next_published(&mut outbound, "reconnecting").await;
+    // the hung attempt gives up and the next one starts
+    next_published(&mut outbound, "reconnecting").await;
+    assert_eq!(fake.connects.load(Ordering::SeqCst), 2);
+    // past the backoff delay, so the disconnect lands mid attempt
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(fake.connects.load(Ordering::SeqCst), 3);
+    // too busy to answer, but still listed
+    let statuses = state.server_statuses().await;
+    assert_eq!(statuses[0].server_id, "server");
+    assert!(!statuses[0].responding);
+    assert!(!statuses[0].connected);
+    handle.send(ServerCommand::Disconnect);
+    while state.get_server("server").await.is_some() {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(state.store.all().unwrap().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn servers_disconnected_while_restoring_stay_gone() {
+    let mut state = MainState::new(Config::default(), ServerStore::temporary().unwrap());
+    state.integrations.register("hung", |_| {
+        Ok(Box::new(FakeIntegration {
+            hangs_on_connect: true,
+            ..Default::default()
+        }))
+    });
+    state
+        .store
+        .save(&StoredServer {
+            server_id: "restored".to_owned(),
+            integration_type: "hung".to_owned(),
+            credentials: credentials_json(&json!({})),
+            polling: None,
+        })
+        .unwrap();
+    let state = Arc::new(state);
+    let (publisher, mut outbound) = Publisher::new();
+    connection::restore_servers(state.clone(), publisher.clone()).await;
+    // mid connect attempt
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    router::disconnect_server(
+        &state,
+        &publisher,
+        "restored".to_owned(),
+        &Correlation::default(),
+    )
+    .await;
+    next_published(&mut outbound, "disconnected").await;
+    // past the connect timeout
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    assert!(state.get_server("restored").await.is_none());
+    assert!(state.store.all().unwrap().is_empty());
+    while let Ok(message) = outbound.try_recv() {
+        let event: Value = serde_json::from_slice(&message.data).unwrap();
+        assert_ne!(event["category"], "restored");
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn nothing_is_sent_while_reconnecting() {
+    let fake = FakeIntegration {
+        hangs_on_reconnect: true,
+        ..Default::default()
+    };
+    let (state, mut outbound) = spawn_actor(fake.clone(), None).await;
+    let handle = state.get_server("server").await.unwrap();
+    fake.drop_connection();
+    next_published(&mut outbound, "reconnecting").await;
+    let passive_requests = fake.passive_requests.load(Ordering::SeqCst);
+    let queued = queued("lamp", "on", ActionPriority::Normal, "queued-1");
+    handle.send(ServerCommand::QueueAction(
+        queued.action_data,
+        queued.correlation,
+    ));
+    handle.send(ServerCommand::DrainQueue);
+    handle.send(ServerCommand::RefreshPassive);
+    handle.send(ServerCommand::Relation {
+        category: "add_relation".to_owned(),
+        data: "lamp".to_owned(),
+        correlation: Correlation {
+            correlation_id: Some("relation-1".to_owned()),
+            reply_to: None,
+        },
+    });
+    let message = next_published(&mut outbound, "error").await;
+    assert_eq!(message.correlation_id.as_deref(), Some("relation-1"));
+    let error: Value = serde_json::from_slice(&message.data).unwrap();
+    assert_eq!(error["data"]["reason"], "server is reconnecting");
+    // through a few timed out connect attempts
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    assert_eq!(fake.actions.load(Ordering::SeqCst), 0);
+    assert_eq!(
+        fake.passive_requests.load(Ordering::SeqCst),
+        passive_requests
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn relations_are_only_confirmed_once_the_integration_took_them() {
+    let (state, mut outbound) = spawn_actor(FakeIntegration::default(), None).await;
+    let (publisher, mut router_outbound) = Publisher::new();
+    route(
+        &state,
+        &publisher,
+        json!({
+            "category": "add_relation",
+            "server_id": "server",
+            "correlation_id": "relation-1",
+            "data": "lamp",
+        }),
+    )
+    .await;
+    let message = next_published(&mut outbound, "error").await;
+    assert_eq!(message.correlation_id.as_deref(), Some("relation-1"));
+    let error: Value = serde_json::from_slice(&message.data).unwrap();
+    assert_eq!(error["data"]["category"], "add_relation");
+    assert_eq!(error["data"]["reason"], "fake does not support relations");
+
+    route(
+        &state,
+        &publisher,
+        json!({
+            "category": "remove_relation",
+            "server_id": "nope",
+            "correlation_id": "relation-2",
+            "data": "lamp",
+        }),
+    )
+    .await;
+    let message = next_published(&mut router_outbound, "error").await;
+    assert_eq!(message.correlation_id.as_deref(), Some("relation-2"));
+    while let Ok(message) = outbound.try_recv() {
+        let event: Value = serde_json::from_slice(&message.data).unwrap();
+        assert_ne!(event["category"], "relation-request-made");
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_waits_for_the_action_in_flight_and_keeps_the_server() {
+    let (state, mut outbound) = spawn_actor(FakeIntegration::default(), None).await;
+    let handle = state.get_server("server").await.unwrap();
+    for bot_name in ["lamp", "fan"] {
+        handle.send(ServerCommand::QueueAction(
+            HOIActionData {
+                bot_name: bot_name.to_owned(),
+                action: "turn_on".to_owned(),
+                priority: ActionPriority::Normal,
+            },
+            Correlation::default(),
+        ));
+    }
+    // lets the first action go out
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    state.shutdown_servers().await;
+
+    let message = next_published(&mut outbound, "action_cancelled").await;
+    let event: Value = serde_json::from_slice(&message.data).unwrap();
+    assert_eq!(event["data"]["bot_name"], "fan");
+    let message = next_published(&mut outbound, "action_timeout").await;
+    let event: Value = serde_json::from_slice(&message.data).unwrap();
+    assert_eq!(event["data"]["retrying"], false);
+    let message = next_published(&mut outbound, "disconnected").await;
+    let event: Value = serde_json::from_slice(&message.data).unwrap();
+    assert_eq!(event["server_id"], "server");
+    assert!(state.get_server("server").await.is_none());
+    // still there to be restored on the next start
+    assert_eq!(state.store.all().unwrap().len(), 1);
+}
+
+fn queued(bot_name: &str, action: &str, priority: ActionPriority, id: &str) -> QueuedAction {
+    QueuedAction {
+        action_data: HOIActionData {
+            bot_name: bot_name.to_owned(),
+            action: action.to_owned(),
+            priority,
+        },
+        correlation: Correlation {
+            correlation_id: Some(id.to_owned()),
+            reply_to: None,
+        },
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn actions_are_coalesced_prioritized_and_cancellable() {
+    let (state, mut outbound) = spawn_actor(FakeIntegration::default(), None).await;
+    let server = state.get_server("server").await.unwrap();
+    for (bot_name, action, priority, id) in [
+        ("lamp", "turn_on", ActionPriority::Normal, "a"),
+        ("fan", "turn_on", ActionPriority::Normal, "b"),
+        ("lamp", "turn_off", ActionPriority::Normal, "c"),
+        ("alarm", "turn_off", ActionPriority::High, "d"),
+    ] {
+        let queued = queued(bot_name, action, priority, id);
+        server.send(ServerCommand::QueueAction(
+            queued.action_data,
+            queued.correlation,
+        ));
+    }
+    let superseded = next_published(&mut outbound, "action_cancelled").await;
+    assert_eq!(superseded.correlation_id.as_deref(), Some("a"));
+
+    server.send(ServerCommand::CancelAction(
+        "fan".to_owned(),
+        Correlation::default(),
+    ));
+    let cancelled = next_published(&mut outbound, "action_cancelled").await;
+    assert_eq!(cancelled.correlation_id.as_deref(), Some("b"));
+
+    server.send(ServerCommand::ListActions(Correlation::default()));
+    let pending = next_published(&mut outbound, "pending_actions").await;
+    let pending: Value = serde_json::from_slice(&pending.data).unwrap();
+    let order: Vec<&str> = pending["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|action| action["bot_name"].as_str().unwrap())
+        .collect();
+    assert_eq!(order, ["alarm", "lamp"]);
+    assert_eq!(pending["data"][1]["action"], "turn_off");
+}
+
+#[test]
+fn full_action_queue_rejects_new_bots() {
+    let mut queue = ActionQueue::new(1);
+    assert!(matches!(
+        queue.push(queued("lamp", "on", ActionPriority::Normal, "a")),
+        Pushed::Queued
+    ));
+    assert!(matches!(
+        queue.push(queued("fan", "on", ActionPriority::High, "b")),
+        Pushed::Full(_)
+    ));
+    // replacing the pending action of a bot never needs room
+    assert!(matches!(
+        queue.push(queued("lamp", "off", ActionPriority::Normal, "c")),
+        Pushed::Replaced(_)
+    ));
+}
+
+fn frames_from(rx: UnboundedReceiver<String>) -> InboundFrames {
+    futures_util::stream::unfold(rx, |mut rx| async move {
+        let frame = rx.recv().await?;
+        Some((frame, rx))
+    })
+    .boxed()
+}
+
+#[tokio::test(start_paused = true)]
+async fn unchanged_passive_data_backs_off() {
+    let fake = FakeIntegration::answering_passive();
+    let (_state, _outbound) = spawn_actor(
+        fake.clone(),
+        Some(PollingConfig {
+            passive_interval_ms: Some(1000),
+            max_passive_interval_ms: Some(8000),
+            ..Default::default()
+        }),
+    )
+    .await;
+    // requested at 1s, 2s, 4s, 8s and 16s
+    tokio::time::sleep(Duration::from_millis(20_500)).await;
+    assert_eq!(fake.passive_requests.load(Ordering::SeqCst), 5);
+}
+
+#[tokio::test(start_paused = true)]
+async fn stale_passive_data_is_requested_once_the_action_is_done() {
+    let fake = FakeIntegration::answering_passive();
+    let (state, _outbound) = spawn_actor(
+        fake.clone(),
+        Some(PollingConfig {
+            action_interval_ms: Some(100),
+            passive_interval_ms: Some(1000),
+            max_staleness_ms: Some(3000),
+            ..Default::default()
+        }),
+    )
+    .await;
+    let queued = queued("lamp", "on", ActionPriority::Normal, "a");
+    state
+        .get_server("server")
+        .await
+        .unwrap()
+        .send(ServerCommand::QueueAction(
+            queued.action_data,
+            queued.correlation,
+        ));
+    // the action is never answered, the data turns stale after 3s but
+    // passive data waits until the action and its retry timed out
+    tokio::time::sleep(Duration::from_millis(3_500)).await;
+    assert_eq!(fake.passive_requests.load(Ordering::SeqCst), 0);
+    tokio::time::sleep(Duration::from_millis(16_000)).await;
+    assert_eq!(fake.passive_requests.load(Ordering::SeqCst), 0);
+    tokio::time::sleep(Duration::from_millis(4_500)).await;
+    assert!(fake.passive_requests.load(Ordering::SeqCst) >= 1);
+}
+
+#[test]
+fn passive_snapshots_are_diffed_into_device_events() {
+    let mut diff = PassiveDiff::default();
+    let on_off = |id: &str, on: bool| {
+        let mut device = new_device(id, id, "switch");
+        set_on_off(&mut device, on);
+        device
+    };
+    let (lamp, fan, plug) = (
+        on_off("lamp", false),
+        on_off("fan", false),
+        on_off("plug", true),
+    );
+
+    assert!(matches!(
+        diff.update(&[lamp.clone(), fan.clone()]),
+        PassiveUpdate::Full
+    ));
+    // only being seen again isn't a change
+    let mut seen_again = lamp.clone();
+    seen_again.last_seen += 5000;
+    match diff.update(&[seen_again, fan.clone()]) {
+        PassiveUpdate::Changes(events) => assert!(events.is_empty()),
+        PassiveUpdate::Full => panic!("expected changes"),
+    }
+    let lamp_on = on_off("lamp", true);
+    match diff.update(&[lamp_on.clone(), plug.clone()]) {
+        PassiveUpdate::Changes(events) => {
+            let events: Vec<Value> = events
+                .into_iter()
+                .map(|event| serde_json::to_value(event).unwrap())
+                .collect();
+            let event = |category: &str, device: &Device| json!({"category": category, "data": serde_json::to_value(device).unwrap()});
+            assert_eq!(events.len(), 3);
+            assert!(events.contains(&event("device_state_changed", &lamp_on)));
+            assert!(events.contains(&event("device_added", &plug)));
+            assert!(events.contains(&event("device_removed", &fan)));
+        }
+        PassiveUpdate::Full => panic!("expected changes"),
+    }
+
+    diff.reset();
+    assert!(matches!(diff.update(&[plug]), PassiveUpdate::Full));
+}
+
+#[tokio::test]
+async fn house_of_iot_bots_and_responses_are_normalized() {
+    let mut integration = HouseOfIoT::new(HouseOfIoTCredentials {
+        connection_str: "ws://localhost".to_owned(),
+        name_and_type: "bors".to_owned(),
+        password: "password".into(),
+        admin_password: "admin".into(),
+        outside_name: "home".to_owned(),
+        user_id: 1,
+    });
+    let bots = json!({"bots": [
+        {"active_status": true, "device_name": "lamp", "device_type": "rgb_light", "brightness": 80},
+        {"device_name": "broken"},
+    ]});
+    match integration.handle_inbound_frame(bots.to_string()).await {
+        InboundEvent::PassiveData(devices) => {
+            assert_eq!(devices.len(), 1);
+            let lamp = device(&devices, "lamp");
+            assert_eq!(lamp.device_type, "rgb_light");
+            assert_eq!(lamp.state["on_off"], true);
+            assert_eq!(lamp.state["brightness"], 80);
+        }
+        _ => panic!("expected passive data"),
+    }
+
+    let response = json!({"bot_name": "lamp", "action": "turn_off", "status": "success"});
+    match integration.handle_inbound_frame(response.to_string()).await {
+        InboundEvent::ActionResponse(result) => {
+            assert_eq!(result.device_id, "lamp");
+            assert_eq!(result.action, "turn_off");
+            assert!(result.success);
+        }
+        _ => panic!("expected an action response"),
+    }
+}
+
+#[test]
+fn pipeline_filters_and_reshapes_devices() {
+    let config: Config = toml::from_str(
+        r#"
+        [filters.default]
+        deny_types = ["light"]
+
+        [filters.home_assistant]
+        deny_ids = ["sensor.*_battery"]
+        deny_names = ["Garage *"]
+        thresholds = { temperature = { max = 30 } }
+        convert = { temperature = "°F" }
+        fields = ["temperature", "on_off"]
+        rename = { temperature = "temp" }
+        "#,
+    )
+    .unwrap();
+    let reading = |id: &str, key: &str, value: Value| {
+        let mut device = new_device(id, id, "sensor");
+        set_reading(&mut device, key, value);
+        set_reading(&mut device, "linkquality", json!(90));
+        device
+    };
+    let devices = vec![
+        reading("sensor.kitchen", "temperature", json!(21)),
+        reading("sensor.oven", "temperature", json!(180)),
+        reading("sensor.door_battery", "battery", json!(80)),
+        new_device("light.hall", "light.hall", "light"),
+        new_device("switch.door", "Garage door", "switch"),
+    ];
+
+    let filtered = config
+        .pipeline_for("server", "home_assistant")
+        .apply(devices.clone());
+    assert_eq!(filtered.len(), 3);
+    let kitchen = device(&filtered, "sensor.kitchen");
+    assert_eq!(kitchen.capabilities, ["temp"]);
+    assert_eq!(kitchen.state["temp"], 69.8);
+    assert_eq!(kitchen.units["temp"], "°F");
+    // out of bounds only drops the reading, not the device
+    let oven = device(&filtered, "sensor.oven");
+    assert!(oven.state.is_empty());
+    assert!(oven.units.is_empty());
+    // the integration's pipeline replaces the default one
+    assert!(filtered.iter().any(|device| device.id == "light.hall"));
+    assert!(filtered.iter().all(|device| device.id != "switch.door"));
+
+    let filtered = config.pipeline_for("server", "mqtt").apply(devices);
+    assert_eq!(filtered.len(), 4);
+    assert!(filtered.iter().all(|device| device.device_type != "light"));
+}
+
+/// Scripted stand in for a House of IoT server. Bots are switched by
+/// turn_on/turn_off actions, so passive data follows the actions.
+#[derive(Clone)]
+pub(crate) struct MockHoi {
+    password: &'static str,
+    /// If set, bot_control first answers needs-admin-auth
+    /// and only goes ahead with this password
+    admin_password: Option<&'static str>,
+    bots: Arc<std::sync::Mutex<Value>>,
+    /// Every frame received after authentication
+    received: Arc<std::sync::Mutex<Vec<String>>>,
+    /// Successful authentications so far
+    connections: Arc<AtomicU32>,
+    drop_connections: Arc<tokio::sync::Notify>,
+}
+
+impl MockHoi {
+    pub(crate) fn new(password: &'static str, bots: Value) -> Self {
+        Self {
+            password,
+            admin_password: None,
+            bots: Arc::new(std::sync::Mutex::new(bots)),
+            received: Default::default(),
+            connections: Default::default(),
+            drop_connections: Default::default(),
+        }
+    }
+
+    fn with_admin_password(mut self, admin_password: &'static str) -> Self {
+        self.admin_password = Some(admin_password);
+        self
+    }
+
+    fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Kills every open connection without a close frame
+    fn drop_connections(&self) {
+        self.drop_connections.notify_waiters();
+    }
+
+    pub(crate) async fn spawn(&self) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mock = self.clone();
+        tokio::task::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::task::spawn(mock.clone().serve(stream));
+            }
+        });
+        format!("ws://{}", addr)
+    }
+
+    async fn serve(self, stream: tokio::net::TcpStream) {
+        let mut ws = accept_async(stream).await.unwrap();
+        // password, name_and_type, outside_name
+        let mut auth = Vec::new();
+        for _ in 0..3 {
+            match next_text(&mut ws).await {
+                Some(frame) => auth.push(frame),
+                None => return,
+            }
+        }
+        if auth[0] != self.password {
+            ws.send(Message::Text("failed".to_owned()))
+                .await
+                .unwrap_or_default();
+            return;
+        }
+        ws.send(Message::Text("success".to_owned())).await.unwrap();
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        loop {
+            let frame = tokio::select! {
+                frame = next_text(&mut ws) => match frame {
+                    Some(frame) => frame,
+                    None => return,
+                },
+                // dropping the socket without a close frame
+                _ = self.drop_connections.notified() => return,
+            };
+            self.received.lock().unwrap().push(frame.clone());
+            let reply = match frame.as_str() {
+                "passive_data" => json!({"bots": self.bots.lock().unwrap().clone()}),
+                "bot_control" => match self.bot_control(&mut ws).await {
+                    Some(reply) => reply,
+                    None => return,
+                },
+                _ => continue,
+            };
+            ws.send(Message::Text(reply.to_string())).await.unwrap();
+        }
+    }
+
+    /// Reads the action and bot_name that follow bot_control
+    /// and runs the action, returning the response
+    async fn bot_control(
+        &self,
+        ws: &mut tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+    ) -> Option<Value> {
+        let action = next_text(ws).await?;
+        let bot_name = next_text(ws).await?;
+        let mut status = "success";
+        if let Some(admin_password) = self.admin_password {
+            ws.send(Message::Text(
+                json!({"status": "needs-admin-auth"}).to_string(),
+            ))
+            .await
+            .ok()?;
+            let given = next_text(ws).await?;
+            self.received.lock().unwrap().push(given.clone());
+            if given != admin_password {
+                status = "failed";
+            }
+        }
+        if status == "success" {
+            let mut bots = self.bots.lock().unwrap();
+            for bot in bots.as_array_mut().unwrap() {
+                if bot["device_name"] == bot_name.as_str() {
+                    bot["active_status"] = json!(action == "turn_on");
+                }
+            }
+        }
+        Some(json!({
+            "server_name": "mock", "action": action, "status": status, "bot_name": bot_name,
+        }))
+    }
+}
+
+async fn next_text(
+    ws: &mut tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+) -> Option<String> {
+    loop {
+        match ws.next().await? {
+            Ok(Message::Text(text)) => return Some(text),
+            Ok(Message::Close(_)) | Err(_) => return None,
+            Ok(_) => continue,
+        }
+    }
+}
+
+pub(crate) fn hoi_credentials(url: &str, password: &str, admin_password: &str) -> Value {
+    json!({
+        "connection_str": url,
+        "name_and_type": "bors:controller",
+        "password": password,
+        "admin_password": admin_password,
+        "outside_name": "home",
+        "user_id": 7,
+    })
+}
+
+/// Routes a command the way `bus::run` does
+async fn route(state: &Arc<MainState>, publisher: &Publisher, command: Value) {
+    let mut command = command;
+    command["version"] = json!(protocol::PROTOCOL_VERSION);
+    let msg = protocol::decode_command(&command.to_string()).unwrap();
+    // rejections are checked through the error events they publish
+    let _ = router::route_rabbit_message(msg, state, publisher).await;
+}
+
+/// Like `next_published` but fails instead of hanging, for tests on real time
+async fn expect_published(
+    outbound: &mut UnboundedReceiver<OutboundMessage>,
+    category: &str,
+) -> (Value, Option<String>) {
+    let message = tokio::time::timeout(Duration::from_secs(5), next_published(outbound, category))
+        .await
+        .unwrap_or_else(|_| panic!("no {} event published", category));
+    let event = serde_json::from_slice(&message.data).unwrap();
+    (event, message.correlation_id)
+}
+
+pub(crate) fn hoi_state() -> (
+    Arc<MainState>,
+    Publisher,
+    UnboundedReceiver<OutboundMessage>,
+) {
+    let state = Arc::new(MainState::new(
+        Config::default(),
+        ServerStore::temporary().unwrap(),
+    ));
+    let (publisher, outbound) = Publisher::new();
+    (state, publisher, outbound)
+}
+
+/// Keeps tests on real time quick
+pub(crate) fn fast_polling() -> PollingConfig {
+    PollingConfig {
+        action_interval_ms: Some(20),
+        passive_interval_ms: Some(50),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn hoi_connects_runs_actions_and_follows_state_end_to_end() {
+    let mock = MockHoi::new(
+        "password",
+        json!([
+            {"active_status": false, "device_name": "lamp", "device_type": "light"},
+            {"active_status": false, "device_name": "fan", "device_type": "fan"},
+        ]),
+    )
+    .with_admin_password("admin");
+    let url = mock.spawn().await;
+    let (state, publisher, mut outbound) = hoi_state();
+
+    route(
+        &state,
+        &publisher,
+        json!({
+            "category": "connect",
+            "correlation_id": "connect-1",
+            "data": {
+                "integration_type": "hoi",
+                "credentials": hoi_credentials(&url, "password", "admin"),
+                "polling": fast_polling(),
+            },
+        }),
+    )
+    .await;
+    let (auth, correlation_id) = expect_published(&mut outbound, "auth_response").await;
+    assert_eq!(correlation_id.as_deref(), Some("connect-1"));
+    assert_eq!(auth["data"]["passed_auth"], true);
+    assert_eq!(auth["data"]["user_id"], 7);
+    let server_id = auth["server_id"].as_str().unwrap().to_owned();
+    assert_eq!(state.get_server(&server_id).await.unwrap().user_id, 7);
+
+    let (passive, _) = expect_published(&mut outbound, "passive_data").await;
+    assert_eq!(passive["server_id"], server_id.as_str());
+    let devices: Vec<Device> = serde_json::from_value(passive["data"].clone()).unwrap();
+    assert_eq!(device(&devices, "lamp").state["on_off"], false);
+
+    // both go through the queue and the admin auth dance one at a time
+    for (bot_name, correlation_id) in [("lamp", "action-1"), ("fan", "action-2")] {
+        route(
+            &state,
+            &publisher,
+            json!({
+                "category": "action",
+                "server_id": server_id,
+                "correlation_id": correlation_id,
+                "data": {"bot_name": bot_name, "action": "turn_on"},
+            }),
+        )
+        .await;
+    }
+    for (bot_name, expected_correlation) in [("lamp", "action-1"), ("fan", "action-2")] {
+        let (response, correlation_id) = expect_published(&mut outbound, "action_response").await;
+        assert_eq!(correlation_id.as_deref(), Some(expected_correlation));
+        assert_eq!(response["data"]["device_id"], bot_name);
+        assert_eq!(response["data"]["success"], true);
+    }
+    let admin_auths = mock
+        .received()
+        .iter()
+        .filter(|frame| *frame == "admin")
+        .count();
+    assert_eq!(admin_auths, 2);
+
+    let mut switched_on = Vec::new();
+    while switched_on.len() < 2 {
+        let (changed, _) = expect_published(&mut outbound, "device_state_changed").await;
+        assert_eq!(changed["data"]["state"]["on_off"], true);
+        switched_on.push(changed["data"]["id"].as_str().unwrap().to_owned());
+    }
+    switched_on.sort();
+    assert_eq!(switched_on, ["fan", "lamp"]);
+}
+
+#[tokio::test]
+async fn hoi_rejected_credentials_never_register_a_server() {
+    let mock = MockHoi::new("password", json!([]));
+    let url = mock.spawn().await;
+    let (state, publisher, mut outbound) = hoi_state();
+    let integration = state
+        .integrations
+        .create(
+            "hoi",
+            &credentials_json(&hoi_credentials(&url, "wrong", "admin")),
+        )
+        .unwrap();
+
+    connection::connect_and_begin_listening(
+        integration,
+        None,
+        state.clone(),
+        publisher,
+        Correlation::default(),
+    )
+    .await;
+    let (auth, _) = expect_published(&mut outbound, "auth_response").await;
+    assert_eq!(auth["data"]["passed_auth"], false);
+    assert!(auth.get("server_id").is_none());
+    assert!(state.all_servers().await.is_empty());
+    assert_eq!(mock.connections.load(Ordering::SeqCst), 0);
+    assert!(METRICS.auth_failures.with_label_values(&["hoi"]).get() >= 1);
+}
+
+#[tokio::test]
+async fn hoi_action_fails_with_the_wrong_admin_password() {
+    let mock = MockHoi::new(
+        "password",
+        json!([{"active_status": false, "device_name": "lamp", "device_type": "light"}]),
+    )
+    .with_admin_password("admin");
+    let url = mock.spawn().await;
+    let (state, publisher, mut outbound) = hoi_state();
+    let integration = state
+        .integrations
+        .create(
+            "hoi",
+            &credentials_json(&hoi_credentials(&url, "password", "not-admin")),
+        )
+        .unwrap();
+    connection::connect_and_begin_listening(
+        integration,
+        Some(fast_polling()),
+        state.clone(),
+        publisher,
+        Correlation::default(),
+    )
+    .await;
+    let (auth, _) = expect_published(&mut outbound, "auth_response").await;
+    let server_id = auth["server_id"].as_str().unwrap();
+
+    state
+        .get_server(server_id)
+        .await
+        .unwrap()
+        .send(ServerCommand::QueueAction(
+            HOIActionData {
+                bot_name: "lamp".to_owned(),
+                action: "turn_on".to_owned(),
+                priority: ActionPriority::Normal,
+            },
+            Correlation::default(),
+        ));
+    let (response, _) = expect_published(&mut outbound, "action_response").await;
+    assert_eq!(response["data"]["success"], false);
+    assert_eq!(mock.bots.lock().unwrap()[0]["active_status"], false);
+}
+
+#[tokio::test]
+async fn hoi_relations_are_forwarded_and_confirmed() {
+    let mock = MockHoi::new("password", json!([]));
+    let url = mock.spawn().await;
+    let (state, publisher, mut outbound) = hoi_state();
+    let integration = state
+        .integrations
+        .create(
+            "hoi",
+            &credentials_json(&hoi_credentials(&url, "password", "admin")),
+        )
+        .unwrap();
+    connection::connect_and_begin_listening(
+        integration,
+        Some(fast_polling()),
+        state.clone(),
+        publisher.clone(),
+        Correlation::default(),
+    )
+    .await;
+    let (auth, _) = expect_published(&mut outbound, "auth_response").await;
+    let server_id = auth["server_id"].as_str().unwrap();
+
+    route(
+        &state,
+        &publisher,
+        json!({
+            "category": "add_relation",
+            "server_id": server_id,
+            "correlation_id": "relation-1",
+            "data": "lamp",
+        }),
+    )
+    .await;
+    let (made, correlation_id) = expect_published(&mut outbound, "relation-request-made").await;
+    assert_eq!(correlation_id.as_deref(), Some("relation-1"));
+    assert_eq!(made["data"], "add_relation");
+    let request = json!({"category": "add_relation", "data": "lamp"}).to_string();
+    while !mock.received().contains(&request) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let received = mock.received();
+    let at = received.iter().position(|frame| *frame == request).unwrap();
+    assert_eq!(received[at - 1], "external_controller_request");
+}
+
+#[tokio::test]
+async fn hoi_abrupt_disconnect_is_reconnected_under_the_same_server_id() {
+    let mock = MockHoi::new(
+        "password",
+        json!([{"active_status": true, "device_name": "lamp", "device_type": "light"}]),
+    );
+    let url = mock.spawn().await;
+    let (state, publisher, mut outbound) = hoi_state();
+    let integration = state
+        .integrations
+        .create(
+            "hoi",
+            &credentials_json(&hoi_credentials(&url, "password", "admin")),
+        )
+        .unwrap();
+    connection::connect_and_begin_listening(
+        integration,
+        Some(fast_polling()),
+        state.clone(),
+        publisher,
+        Correlation::default(),
+    )
+    .await;
+    let (auth, _) = expect_published(&mut outbound, "auth_response").await;
+    let server_id = auth["server_id"].as_str().unwrap().to_owned();
+    expect_published(&mut outbound, "passive_data").await;
+
+    mock.drop_connections();
+    let (reconnecting, _) = expect_published(&mut outbound, "reconnecting").await;
+    assert_eq!(reconnecting["server_id"], server_id.as_str());
+    let (reconnected, _) = expect_published(&mut outbound, "reconnected").await;
+    assert_eq!(reconnected["server_id"], server_id.as_str());
+    assert_eq!(mock.connections.load(Ordering::SeqCst), 2);
+    // missed changes could have happened, so the snapshot is sent in full again
+    let (passive, _) = expect_published(&mut outbound, "passive_data").await;
+    assert_eq!(passive["server_id"], server_id.as_str());
+    assert!(state.get_server(&server_id).await.is_some());
+}
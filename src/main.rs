@@ -1,35 +1,133 @@
-use communication::rabbit;
-use futures::lock::Mutex;
+use communication::{bus, protocol};
+use integration::connection;
+use state::config::Config;
+use state::persistence::ServerStore;
 use state::state_types::MainState;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
 pub mod integration {
+    pub mod action_queue;
+    pub mod connection;
+    pub mod device;
+    pub mod home_assistant;
     pub mod house_of_iot;
+    pub mod iot_integration;
+    pub mod mqtt;
+    pub mod passive_diff;
+    pub mod pipeline;
+    pub mod registry;
+    pub mod server_actor;
+    #[cfg(test)]
+    pub mod tests;
 }
 pub mod communication {
+    pub mod backoff;
+    pub mod bus;
+    pub mod memory;
+    pub mod nats;
+    pub mod protocol;
     pub mod rabbit;
+    pub mod redis_streams;
     pub mod router;
+    pub mod secret;
+    #[cfg(test)]
+    pub mod tests;
     pub mod types;
 }
 
+pub mod http {
+    pub mod admin;
+    pub mod health;
+    pub mod metrics;
+    #[cfg(test)]
+    pub mod tests;
+}
+
 pub mod state {
+    pub mod config;
+    pub mod metrics;
+    pub mod persistence;
     pub mod state_types;
+    #[cfg(test)]
+    pub mod tests;
 }
 
 #[tokio::main]
 async fn main() {
-    let main_state = Arc::new(RwLock::new(MainState::new()));
-    let connection = rabbit::setup_rabbit_connection().await;
-    if let Ok(conn) = connection {
-        let publish_channel = rabbit::setup_publish_channel(&conn).await;
-        if let Ok(pub_channel) = publish_channel {
-            let locked_channel = Arc::new(Mutex::new(pub_channel));
-            // run forever by checking our message broker
-            // and executing commands
-            rabbit::setup_consume_task(&conn, main_state.clone(), locked_channel)
-                .await
-                .unwrap();
+    // lets the general server validate against the same contract we use
+    if std::env::args().any(|arg| arg == "--export-schema") {
+        println!("{}", protocol::export_schema());
+        return;
+    }
+    init_logging();
+    let store = ServerStore::open_default().unwrap_or_else(|err| {
+        error!(%err, "failed to open server store, servers won't survive a restart");
+        ServerStore::temporary().unwrap()
+    });
+    let config = Config::load().expect("invalid config file");
+    let main_state = Arc::new(MainState::new(config, store));
+    if let Ok(addr) = std::env::var("BORS_METRICS_ADDR") {
+        tokio::task::spawn(http::metrics::serve(addr));
+    }
+    let (publisher, outbound) = bus::Publisher::new();
+    if let Ok(addr) = std::env::var("BORS_HEALTH_ADDR") {
+        tokio::task::spawn(http::health::serve(addr, main_state.clone()));
+    }
+    if let Ok(addr) = std::env::var("BORS_ADMIN_ADDR") {
+        tokio::task::spawn(http::admin::serve(
+            addr,
+            main_state.clone(),
+            publisher.clone(),
+        ));
+    }
+    // events are buffered until the broker is up, so restoring can start right away
+    tokio::task::spawn(connection::restore_servers(
+        main_state.clone(),
+        publisher.clone(),
+    ));
+    // run until told to stop by checking our message broker
+    // and executing commands
+    let message_bus = bus::from_config(&main_state.config.transport);
+    bus::run(
+        message_bus,
+        main_state,
+        publisher,
+        outbound,
+        shutdown_signal(),
+    )
+    .await;
+}
+
+/// Logs to stderr, filtered by BORS_LOG (e.g. "info" or
+/// "warn,Bors::integration=debug") and as json lines if
+/// BORS_LOG_FORMAT=json
+fn init_logging() {
+    let filter = EnvFilter::try_from_env("BORS_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+    // stdout is left to the events of the memory transport
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    if std::env::var("BORS_LOG_FORMAT").as_deref() == Ok("json") {
+        logger.json().with_current_span(true).init();
+    } else {
+        logger.init();
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
         }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap_or_default();
+    info!("shutting down, finishing in-flight actions");
 }
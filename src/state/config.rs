@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::communication::types::PollingConfig;
+use crate::integration::pipeline::Pipeline;
+
+const DEFAULT_ACTION_INTERVAL_MS: u64 = 1700;
+const DEFAULT_PASSIVE_INTERVAL_MS: u64 = 5000;
+const DEFAULT_MAX_PASSIVE_INTERVAL_MS: u64 = 30_000;
+const DEFAULT_MAX_STALENESS_MS: u64 = 60_000;
+const DEFAULT_ACTION_RETRIES: u32 = 1;
+
+/// Settings read from the config file at BORS_CONFIG (./bors.toml by default)
+#[derive(Deserialize, Default)]
+pub struct Config {
+    /// Poll timing keyed by integration type, "default" applies to all
+    #[serde(default)]
+    pub polling: HashMap<String, PollingConfig>,
+    /// Device pipelines keyed by server_id or integration type,
+    /// "default" applies to servers without a pipeline of their own
+    #[serde(default)]
+    pub filters: HashMap<String, Pipeline>,
+    #[serde(default)]
+    pub transport: TransportConfig,
+}
+
+/// Which broker connects us to the general server
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TransportConfig {
+    /// RabbitMQ, `addr` falls back to AMQP_ADDR and then to a local broker
+    Amqp { addr: Option<String> },
+    /// NATS, `url` falls back to NATS_URL and then to a local server.
+    /// Core NATS delivers at most once, JetStream keeps commands until acked
+    Nats {
+        url: Option<String>,
+        #[serde(default)]
+        jetstream: bool,
+    },
+    /// Redis Streams, `url` falls back to REDIS_URL and then to a local server.
+    /// Every Bors instance needs its own `consumer` name
+    Redis {
+        url: Option<String>,
+        consumer: Option<String>,
+    },
+    /// Commands are read from stdin and events printed to stdout
+    Memory,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig::Amqp { addr: None }
+    }
+}
+
+impl Config {
+    /// A missing file just means defaults everywhere
+    pub fn load() -> anyhow::Result<Self> {
+        let path = std::env::var("BORS_CONFIG").unwrap_or_else(|_| "bors.toml".into());
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The most specific pipeline configured for a server
+    pub fn pipeline_for(&self, server_id: &str, integration_type: &str) -> Pipeline {
+        [server_id, integration_type, "default"]
+            .iter()
+            .find_map(|key| self.filters.get(*key))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Poll timing for a server, `overrides` from its connect message win
+    /// over the integration's settings which win over "default".
+    pub fn polling_for(
+        &self,
+        integration_type: &str,
+        overrides: Option<&PollingConfig>,
+    ) -> Polling {
+        let layers = [
+            overrides,
+            self.polling.get(integration_type),
+            self.polling.get("default"),
+        ];
+        let ms = |field: fn(&PollingConfig) -> Option<u64>, fallback: u64| {
+            let ms = layers
+                .iter()
+                .flatten()
+                .find_map(|layer| field(layer))
+                .unwrap_or(fallback);
+            Duration::from_millis(ms.max(1))
+        };
+        let passive_interval = ms(|p| p.passive_interval_ms, DEFAULT_PASSIVE_INTERVAL_MS);
+        Polling {
+            action_interval: ms(|p| p.action_interval_ms, DEFAULT_ACTION_INTERVAL_MS),
+            passive_interval,
+            max_passive_interval: ms(
+                |p| p.max_passive_interval_ms,
+                DEFAULT_MAX_PASSIVE_INTERVAL_MS,
+            )
+            .max(passive_interval),
+            max_staleness: ms(|p| p.max_staleness_ms, DEFAULT_MAX_STALENESS_MS),
+            full_snapshot_interval: layers
+                .iter()
+                .flatten()
+                .find_map(|layer| layer.full_snapshot_interval_ms)
+                .map(Duration::from_millis),
+            action_retries: layers
+                .iter()
+                .flatten()
+                .find_map(|layer| layer.action_retries)
+                .unwrap_or(DEFAULT_ACTION_RETRIES),
+        }
+    }
+}
+
+/// Resolved poll timing of a single server
+#[derive(Clone, Copy, Debug)]
+pub struct Polling {
+    pub action_interval: Duration,
+    /// Passive data is requested this often while it keeps changing
+    pub passive_interval: Duration,
+    /// Unchanged passive data backs the interval off up to this
+    pub max_passive_interval: Duration,
+    /// Passive data is requested at least this often, once it's
+    /// stale it goes out before the next action is sent
+    pub max_staleness: Duration,
+    pub full_snapshot_interval: Option<Duration>,
+    /// Times a timed out action is sent again
+    pub action_retries: u32,
+}
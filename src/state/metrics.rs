@@ -0,0 +1,135 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+/// Every metric Bors exposes, registered in their own registry
+/// so `/metrics` only ever shows ours.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Labeled by integration type
+    pub connected_servers: IntGaugeVec,
+    /// Labeled by server_id
+    pub queue_depth: IntGaugeVec,
+    /// Labeled by integration type and outcome: executed, failed or timed_out
+    pub actions: IntCounterVec,
+    /// Labeled by integration type
+    pub passive_poll_latency: HistogramVec,
+    /// Labeled by integration type
+    pub auth_failures: IntCounterVec,
+    /// Labeled by command category
+    pub commands: IntCounterVec,
+    pub publish_failures: IntCounter,
+    pub broker_reconnects: IntCounter,
+    pub command_retries: IntCounter,
+    pub dead_letters: IntCounter,
+    /// Labeled by integration type
+    pub server_reconnects: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Self {
+            connected_servers: IntGaugeVec::new(
+                Opts::new("bors_connected_servers", "Connected IoT servers"),
+                &["integration"],
+            )
+            .unwrap(),
+            queue_depth: IntGaugeVec::new(
+                Opts::new("bors_queue_depth", "Actions waiting to be sent"),
+                &["server_id"],
+            )
+            .unwrap(),
+            actions: IntCounterVec::new(
+                Opts::new("bors_actions_total", "Actions by outcome"),
+                &["integration", "outcome"],
+            )
+            .unwrap(),
+            passive_poll_latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "bors_passive_poll_latency_seconds",
+                    "Time from requesting passive data to receiving it",
+                ),
+                &["integration"],
+            )
+            .unwrap(),
+            auth_failures: IntCounterVec::new(
+                Opts::new(
+                    "bors_auth_failures_total",
+                    "IoT servers that rejected our credentials",
+                ),
+                &["integration"],
+            )
+            .unwrap(),
+            commands: IntCounterVec::new(
+                Opts::new("bors_commands_total", "Commands routed by category"),
+                &["category"],
+            )
+            .unwrap(),
+            publish_failures: IntCounter::new(
+                "bors_publish_failures_total",
+                "Messages the broker failed to take or refused",
+            )
+            .unwrap(),
+            broker_reconnects: IntCounter::new(
+                "bors_broker_reconnects_total",
+                "Times the broker connection was lost and set up again",
+            )
+            .unwrap(),
+            command_retries: IntCounter::new(
+                "bors_command_retries_total",
+                "Commands routed again after a transient failure",
+            )
+            .unwrap(),
+            dead_letters: IntCounter::new(
+                "bors_dead_letters_total",
+                "Commands given up on and sent to the dead letter queue",
+            )
+            .unwrap(),
+            server_reconnects: IntCounterVec::new(
+                Opts::new(
+                    "bors_server_reconnects_total",
+                    "Reconnect attempts to IoT servers",
+                ),
+                &["integration"],
+            )
+            .unwrap(),
+            registry,
+        };
+        metrics.register();
+        metrics
+    }
+
+    fn register(&self) {
+        let collectors: [Box<dyn prometheus::core::Collector>; 11] = [
+            Box::new(self.connected_servers.clone()),
+            Box::new(self.queue_depth.clone()),
+            Box::new(self.actions.clone()),
+            Box::new(self.passive_poll_latency.clone()),
+            Box::new(self.auth_failures.clone()),
+            Box::new(self.commands.clone()),
+            Box::new(self.publish_failures.clone()),
+            Box::new(self.broker_reconnects.clone()),
+            Box::new(self.command_retries.clone()),
+            Box::new(self.dead_letters.clone()),
+            Box::new(self.server_reconnects.clone()),
+        ];
+        for collector in collectors {
+            self.registry.register(collector).unwrap();
+        }
+    }
+
+    /// Everything in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
@@ -0,0 +1,160 @@
+use crate::communication::types::PollingConfig;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use zeroize::Zeroizing;
+
+/// Everything needed to reconnect a server after a restart
+#[derive(Clone)]
+pub struct StoredServer {
+    pub server_id: String,
+    pub integration_type: String,
+    /// As JSON, the way the integration serialized them
+    pub credentials: Zeroizing<Vec<u8>>,
+    pub polling: Option<PollingConfig>,
+}
+
+/// How a server is written to disk, the credentials
+/// are only ever stored encrypted.
+#[derive(Deserialize, Serialize)]
+struct EncryptedServer {
+    server_id: String,
+    integration_type: String,
+    #[serde(default)]
+    polling: Option<PollingConfig>,
+    /// Hex encoded XChaCha20-Poly1305 nonce and ciphertext
+    nonce: String,
+    credentials: String,
+}
+
+/// Embedded store of every connected server, keyed by server_id,
+/// so a restart of Bors doesn't drop them.
+pub struct ServerStore {
+    db: sled::Db,
+    cipher: XChaCha20Poly1305,
+}
+
+impl ServerStore {
+    /// Opens the store at BORS_DB_PATH (./bors_db by default), encrypted
+    /// with the key from BORS_SECRET_KEY or the file at BORS_SECRET_KEY_FILE
+    pub fn open_default() -> anyhow::Result<Self> {
+        let path = std::env::var("BORS_DB_PATH").unwrap_or_else(|_| "bors_db".into());
+        Self::open(&path, &load_key()?)
+    }
+
+    /// `key` is the 32 byte credential key, hex encoded
+    pub fn open(path: &str, key: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+            cipher: cipher_from_hex(key)?,
+        })
+    }
+
+    /// Store that lives only as long as the process, used when
+    /// persistence isn't wanted or the real store can't be opened.
+    pub fn temporary() -> anyhow::Result<Self> {
+        Ok(Self {
+            db: sled::Config::new().temporary(true).open()?,
+            cipher: XChaCha20Poly1305::new(&XChaCha20Poly1305::generate_key(&mut OsRng)),
+        })
+    }
+
+    pub fn save(&self, server: &StoredServer) -> anyhow::Result<()> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .cipher
+            .encrypt(&nonce, server.credentials.as_slice())
+            .map_err(|_| anyhow::anyhow!("failed to encrypt credentials"))?;
+        let record = EncryptedServer {
+            server_id: server.server_id.clone(),
+            integration_type: server.integration_type.clone(),
+            polling: server.polling.clone(),
+            nonce: hex::encode(nonce),
+            credentials: hex::encode(encrypted),
+        };
+        self.db
+            .insert(server.server_id.as_bytes(), serde_json::to_vec(&record)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn remove(&self, server_id: &str) -> anyhow::Result<()> {
+        self.db.remove(server_id.as_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Whether the server is persisted, connected or not
+    pub fn contains(&self, server_id: &str) -> bool {
+        self.db
+            .contains_key(server_id.as_bytes())
+            .unwrap_or_default()
+    }
+
+    /// Every persisted server that can be read back. A record that can't
+    /// be (e.g. encrypted with another key) is logged and skipped, but kept
+    /// on disk, so one bad record doesn't stop the others from being restored.
+    pub fn all(&self) -> anyhow::Result<Vec<StoredServer>> {
+        let mut servers = Vec::new();
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            let server_id = String::from_utf8_lossy(&key);
+            // serde errors can quote the record, so they are never logged
+            let Ok(record) = serde_json::from_slice(&value) else {
+                warn!(%server_id, "skipping persisted server that isn't a valid record");
+                continue;
+            };
+            match self.decrypt(record) {
+                Ok(server) => servers.push(server),
+                Err(err) => warn!(%server_id, %err, "skipping persisted server"),
+            }
+        }
+        Ok(servers)
+    }
+
+    fn decrypt(&self, record: EncryptedServer) -> anyhow::Result<StoredServer> {
+        let nonce = hex::decode(&record.nonce)?;
+        if nonce.len() != 24 {
+            anyhow::bail!("invalid nonce for server {}", record.server_id);
+        }
+        let credentials = Zeroizing::new(
+            self.cipher
+                .decrypt(
+                    XNonce::from_slice(&nonce),
+                    hex::decode(&record.credentials)?.as_slice(),
+                )
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "failed to decrypt credentials of server {}, wrong key?",
+                        record.server_id
+                    )
+                })?,
+        );
+        Ok(StoredServer {
+            server_id: record.server_id,
+            integration_type: record.integration_type,
+            polling: record.polling,
+            credentials,
+        })
+    }
+}
+
+/// Reads the hex encoded credential key from the environment
+fn load_key() -> anyhow::Result<Zeroizing<String>> {
+    if let Ok(key) = std::env::var("BORS_SECRET_KEY") {
+        return Ok(Zeroizing::new(key));
+    }
+    match std::env::var("BORS_SECRET_KEY_FILE") {
+        Ok(path) => Ok(Zeroizing::new(std::fs::read_to_string(path)?)),
+        Err(_) => anyhow::bail!("neither BORS_SECRET_KEY nor BORS_SECRET_KEY_FILE is set"),
+    }
+}
+
+fn cipher_from_hex(key: &str) -> anyhow::Result<XChaCha20Poly1305> {
+    let key = Zeroizing::new(hex::decode(key.trim())?);
+    if key.len() != 32 {
+        anyhow::bail!("the credential key must be 32 bytes, hex encoded");
+    }
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
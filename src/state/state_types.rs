@@ -1,42 +1,131 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::communication::types::{HOIActionData, HouseOfIoTCredentials};
-use futures_channel::mpsc::UnboundedSender;
-use queues::*;
-use tokio_tungstenite::tungstenite;
+use crate::integration::{
+    registry::IntegrationRegistry,
+    server_actor::{ServerCommand, ServerHandle, ServerStatus},
+};
+use crate::state::config::Config;
+use crate::state::metrics::METRICS;
+use crate::state::persistence::{ServerStore, StoredServer};
+use futures_util::future::join_all;
+use tokio::sync::RwLock;
+use tracing::error;
 
+/// Shared directory of every connected IoT server.
+///
+/// Per server state (queues, in-progress flags, the connection itself)
+/// is owned by that server's actor, this only keeps the handles used
+/// to reach them so the lock is never held for longer than a lookup.
 pub struct MainState {
-    /// Holds the sender portion of the server mpsc channel
-    /// so when we need to send messages to the server we
-    /// relay the message to this channel which has an async
-    /// task that does the rest by forwarding directly over
-    /// the real connection.
-    pub server_connections: HashMap<String, UnboundedSender<tungstenite::protocol::Message>>,
-    pub server_credentials: HashMap<String, HouseOfIoTCredentials>,
-    pub action_execution_queue: HashMap<String, Queue<HOIActionData>>,
-    /// Keeping track of actions in progress to never
-    /// have two actions running at once which won't work
-    /// with some IoT servers especially HOI.
-    pub action_in_progress: HashMap<String, bool>,
-    /// Keeping track of what passive data requests
-    /// are in progress, HOI can't have an action + passive
-    /// data in progress.
-    pub passive_in_progress: HashMap<String, bool>,
-    /// Keeping track of how many times we skipped over
-    /// passive data in order to do a mandatory force
-    /// request after every 7 skips
-    pub passive_data_skips: HashMap<String, u8>,
+    /// Every integration type Bors knows how to connect to
+    pub integrations: IntegrationRegistry,
+    servers: RwLock<HashMap<String, ServerHandle>>,
+    /// Mirrors `servers` on disk so they survive a restart
+    pub store: ServerStore,
+    pub config: Config,
+    pub broker: BrokerStatus,
+}
+
+/// Kept up to date by `bus::run` for the readiness endpoint
+#[derive(Default)]
+pub struct BrokerStatus {
+    connected: AtomicBool,
+    consuming: AtomicBool,
+}
+
+impl BrokerStatus {
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Relaxed);
+        if !connected {
+            self.set_consuming(false);
+        }
+    }
+
+    pub fn set_consuming(&self, consuming: bool) {
+        self.consuming.store(consuming, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn consuming(&self) -> bool {
+        self.consuming.load(Ordering::Relaxed)
+    }
 }
 
 impl MainState {
-    pub fn new() -> Self {
+    pub fn new(config: Config, store: ServerStore) -> Self {
         Self {
-            server_connections: HashMap::new(),
-            server_credentials: HashMap::new(),
-            action_execution_queue: HashMap::new(),
-            action_in_progress: HashMap::new(),
-            passive_in_progress: HashMap::new(),
-            passive_data_skips: HashMap::new(),
+            integrations: IntegrationRegistry::with_defaults(),
+            servers: RwLock::new(HashMap::new()),
+            store,
+            config,
+            broker: BrokerStatus::default(),
+        }
+    }
+
+    pub async fn insert_server(&self, handle: ServerHandle, stored: StoredServer) {
+        if let Err(err) = self.store.save(&stored) {
+            error!(server_id = %stored.server_id, %err, "failed to persist server");
+        }
+        METRICS
+            .connected_servers
+            .with_label_values(&[handle.integration_type])
+            .inc();
+        self.servers
+            .write()
+            .await
+            .insert(handle.server_id.clone(), handle);
+    }
+
+    pub async fn all_servers(&self) -> Vec<ServerHandle> {
+        self.servers.read().await.values().cloned().collect()
+    }
+
+    /// Asks every server for its status at once, so one slow
+    /// actor holds up the answer by at most its inspect timeout
+    pub async fn server_statuses(&self) -> Vec<ServerStatus> {
+        let servers = self.all_servers().await;
+        let mut statuses = join_all(servers.iter().map(ServerHandle::report)).await;
+        statuses.sort_by(|a, b| a.server_id.cmp(&b.server_id));
+        statuses
+    }
+
+    pub async fn get_server(&self, server_id: &str) -> Option<ServerHandle> {
+        self.servers.read().await.get(server_id).cloned()
+    }
+
+    pub async fn remove_server(&self, server_id: &str) -> Option<ServerHandle> {
+        if let Err(err) = self.store.remove(server_id) {
+            error!(%server_id, %err, "failed to remove persisted server");
+        }
+        self.unregister_server(server_id).await
+    }
+
+    /// Drops the server from the directory but keeps it
+    /// persisted, so it is restored on the next start
+    pub async fn unregister_server(&self, server_id: &str) -> Option<ServerHandle> {
+        let removed = self.servers.write().await.remove(server_id);
+        if let Some(handle) = &removed {
+            METRICS
+                .connected_servers
+                .with_label_values(&[handle.integration_type])
+                .dec();
+        }
+        removed
+    }
+
+    /// Asks every server to shut down and waits until all of them
+    /// have, which is bounded by the action timeout
+    pub async fn shutdown_servers(&self) {
+        let handles = self.all_servers().await;
+        for handle in &handles {
+            handle.send(ServerCommand::Shutdown);
+        }
+        for handle in &handles {
+            handle.stopped().await;
         }
     }
 }
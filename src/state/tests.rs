@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::communication::secret::credentials_json;
+use crate::communication::types::PollingConfig;
+use crate::integration::iot_integration::IoTIntegration;
+use crate::integration::registry::IntegrationRegistry;
+use crate::integration::tests::home_assistant;
+
+use super::config::{Config, TransportConfig};
+use super::persistence::{ServerStore, StoredServer};
+
+#[test]
+fn server_store_round_trips_servers() {
+    let store = ServerStore::temporary().unwrap();
+    let stored = StoredServer {
+        server_id: "server".to_owned(),
+        integration_type: "home_assistant".to_owned(),
+        credentials: home_assistant("ws://localhost".to_owned(), "token").credentials(),
+        polling: None,
+    };
+    store.save(&stored).unwrap();
+    let all = store.all().unwrap();
+    assert_eq!(all.len(), 1);
+    let credentials: Value = serde_json::from_slice(&all[0].credentials).unwrap();
+    assert_eq!(credentials["access_token"], "token");
+    // the stored credentials must be enough to recreate the integration
+    let registry = IntegrationRegistry::with_defaults();
+    assert!(registry
+        .create(&all[0].integration_type, &all[0].credentials)
+        .is_ok());
+
+    store.remove("server").unwrap();
+    assert!(store.all().unwrap().is_empty());
+}
+
+/// sled lets go of its lock a moment after the previous store is dropped
+fn reopen_store(path: &str, key: &str) -> ServerStore {
+    for _ in 0..100 {
+        if let Ok(store) = ServerStore::open(path, key) {
+            return store;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    panic!("couldn't reopen the server store");
+}
+
+#[test]
+fn server_store_encrypts_credentials() {
+    let path = std::env::temp_dir().join(format!("bors-test-{}", uuid::Uuid::new_v4()));
+    let path = path.to_str().unwrap();
+    let key = "11".repeat(32);
+    let store = ServerStore::open(path, &key).unwrap();
+    store
+        .save(&StoredServer {
+            server_id: "server".to_owned(),
+            integration_type: "home_assistant".to_owned(),
+            credentials: credentials_json(&json!({"access_token": "very-secret-token"})),
+            polling: None,
+        })
+        .unwrap();
+    drop(store);
+
+    let on_disk: Vec<u8> = std::fs::read_dir(path)
+        .unwrap()
+        .flat_map(|entry| std::fs::read(entry.unwrap().path()).unwrap_or_default())
+        .collect();
+    assert!(!String::from_utf8_lossy(&on_disk).contains("very-secret-token"));
+
+    // unreadable with another key, but left alone for the right one
+    let wrong_key = reopen_store(path, &"22".repeat(32));
+    assert!(wrong_key.all().unwrap().is_empty());
+    assert!(wrong_key.contains("server"));
+    wrong_key
+        .save(&StoredServer {
+            server_id: "other".to_owned(),
+            integration_type: "home_assistant".to_owned(),
+            credentials: credentials_json(&json!({"access_token": "other-token"})),
+            polling: None,
+        })
+        .unwrap();
+    assert_eq!(wrong_key.all().unwrap().len(), 1);
+    drop(wrong_key);
+    // one unreadable record doesn't keep the others from being restored
+    let store = reopen_store(path, &key);
+    let all = store.all().unwrap();
+    assert_eq!(all.len(), 1);
+    let credentials: Value = serde_json::from_slice(&all[0].credentials).unwrap();
+    assert_eq!(credentials["access_token"], "very-secret-token");
+    drop(store);
+    std::fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn polling_config_layers_connect_integration_and_default() {
+    let config: Config = toml::from_str(
+        r#"
+        [polling.default]
+        passive_interval_ms = 2000
+        max_staleness_ms = 9000
+
+        [polling.mqtt]
+        passive_interval_ms = 3000
+        action_retries = 0
+        "#,
+    )
+    .unwrap();
+    let overrides = PollingConfig {
+        max_staleness_ms: Some(4000),
+        ..Default::default()
+    };
+    let polling = config.polling_for("mqtt", Some(&overrides));
+    assert_eq!(polling.passive_interval, Duration::from_millis(3000));
+    assert_eq!(polling.max_staleness, Duration::from_millis(4000));
+    assert_eq!(polling.action_interval, Duration::from_millis(1700));
+    assert_eq!(polling.action_retries, 0);
+
+    let polling = config.polling_for("hoi", None);
+    assert_eq!(polling.passive_interval, Duration::from_millis(2000));
+    assert_eq!(polling.max_staleness, Duration::from_millis(9000));
+    assert_eq!(polling.action_retries, 1);
+}
+
+#[test]
+fn transport_is_selected_by_config() {
+    let transport = |toml: &str| toml::from_str::<Config>(toml).map(|config| config.transport);
+    assert_eq!(transport("").unwrap(), TransportConfig::Amqp { addr: None });
+    assert_eq!(
+        transport("[transport]\ntype = \"nats\"\njetstream = true").unwrap(),
+        TransportConfig::Nats {
+            url: None,
+            jetstream: true
+        }
+    );
+    assert_eq!(
+        transport(
+            "[transport]\ntype = \"redis\"\nurl = \"redis://cache:6379\"\nconsumer = \"bors-2\""
+        )
+        .unwrap(),
+        TransportConfig::Redis {
+            url: Some("redis://cache:6379".to_owned()),
+            consumer: Some("bors-2".to_owned()),
+        }
+    );
+    // a typo shouldn't quietly fall back to another broker
+    assert!(transport("[transport]\ntype = \"nats\"\njetstrem = true").is_err());
+    assert!(transport("[transport]\ntype = \"kafka\"").is_err());
+}